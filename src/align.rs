@@ -0,0 +1,292 @@
+use crate::moonbase::MoonbaseNote;
+
+// The engine never sings exactly what it's asked to: renders start and
+// end with some silence, and each note comes out a little longer or
+// shorter than requested. This stage finds where the sung passages
+// actually begin and moves them to where the score says they should,
+// lengthening or shortening the silence in between. The sung audio
+// itself is left alone, so a passage that runs long keeps its length
+// and only its start is corrected.
+
+// a sample counts as sound above this fraction of the render's peak
+const SOUND_RATIO: f32 = 0.05;
+const MIN_SOUND_LEVEL: i32 = 64;
+// samples below this are treated as the zero crossing an onset starts at
+const NOISE_FLOOR: i32 = 16;
+const FRAME_MS: usize = 5;
+// an onset needs at least this much silence before it
+const MIN_GAP_MS: usize = 30;
+// how far from its expected position an onset is looked for
+const SEARCH_MS: usize = 250;
+// drift under this is left alone rather than padded or trimmed away
+const TOLERANCE_MS: usize = 2;
+
+pub fn ms_to_samples(ms: i64, sample_rate: u32) -> usize
+{
+    (ms.max(0) * sample_rate as i64 / 1000) as usize
+}
+
+fn sound_threshold(samples: &[i16]) -> i32
+{
+    let peak = samples.iter().map(|s| (*s as i32).abs()).max().unwrap_or(0);
+    ((peak as f32 * SOUND_RATIO) as i32).max(MIN_SOUND_LEVEL)
+}
+
+// sample positions where sound starts after a stretch of silence
+pub fn find_onsets(samples: &[i16], sample_rate: u32) -> Vec<usize>
+{
+    let threshold = sound_threshold(samples);
+    let frame = ms_to_samples(FRAME_MS as i64, sample_rate).max(1);
+    let min_gap = ms_to_samples(MIN_GAP_MS as i64, sample_rate);
+
+    let mut onsets = vec![];
+    let mut last_sound : Option<usize> = None;
+    for (f, chunk) in samples.chunks(frame).enumerate()
+    {
+        let loud = match chunk.iter().position(|s| (*s as i32).abs() >= threshold)
+        {
+            Some(i) => f * frame + i,
+            None => continue,
+        };
+
+        let quiet_since = last_sound.map_or(0, |s| s + 1);
+        if last_sound.is_none() || loud - quiet_since >= min_gap
+        {
+            // back up to the zero crossing the waveform leaves from
+            let mut start = loud;
+            while start > quiet_since && (samples[start - 1] as i32).abs() > NOISE_FLOOR
+            {
+                start -= 1;
+            }
+            onsets.push(start.saturating_sub(1).max(quiet_since));
+        }
+
+        let end = f * frame + chunk.iter().rposition(|s| (*s as i32).abs() >= threshold).unwrap();
+        last_sound = Some(end);
+    }
+    onsets
+}
+
+// one past the last sample of sound
+pub fn sound_end(samples: &[i16]) -> usize
+{
+    last_above(samples, sound_threshold(samples))
+}
+
+fn last_above(samples: &[i16], threshold: i32) -> usize
+{
+    samples.iter().rposition(|s| (*s as i32).abs() >= threshold).map_or(0, |i| i + 1)
+}
+
+// Where a track's sung passages should start, where the last one should
+// end, and how long the whole track is, all in samples. A passage starts
+// at the first note and after every rest.
+pub struct NominalTiming
+{
+    pub onsets: Vec<usize>,
+    pub sound_end: usize,
+    pub len: usize
+}
+
+pub fn nominal_timing(notes: &[MoonbaseNote], sample_rate: u32) -> NominalTiming
+{
+    let mut onsets = vec![];
+    let mut sound_end = 0;
+    let mut ms: i64 = 0;
+    let mut after_rest = true;
+    for n in notes
+    {
+        let rest = n.prefix == "_";
+        if !rest && after_rest
+        {
+            onsets.push(ms_to_samples(ms, sample_rate));
+        }
+        ms += n.dur_ms as i64;
+        if !rest
+        {
+            sound_end = ms_to_samples(ms, sample_rate);
+        }
+        after_rest = rest;
+    }
+
+    NominalTiming { onsets, sound_end, len: ms_to_samples(ms, sample_rate) }
+}
+
+// pairs each nominal onset with the detected onset nearest to where it's
+// expected, starting from the given drift and carrying what's seen forward
+fn match_onsets(detected: &[usize], nominal: &[usize], drift: i64, sample_rate: u32) -> Vec<(usize, usize)>
+{
+    let window = ms_to_samples(SEARCH_MS as i64, sample_rate) as i64;
+    let mut anchors = vec![];
+    let mut drift = drift;
+    let mut next = 0;
+    for n in nominal
+    {
+        let predicted = *n as i64 + drift;
+        let nearest = detected[next..].iter().enumerate()
+            .map(|(i, d)| (i, (*d as i64 - predicted).abs()))
+            .filter(|(_, dist)| *dist <= window)
+            .min_by_key(|(_, dist)| *dist);
+
+        if let Some((i, _)) = nearest
+        {
+            let actual = detected[next + i];
+            anchors.push((actual, *n));
+            drift = actual as i64 - *n as i64;
+            next += i + 1;
+        }
+    }
+    anchors
+}
+
+// Moves a render onto its nominal timing. The result is at least as long
+// as the track; anything sounding past its end (the last note's release)
+// is kept so it can overlap what follows, less the backend's tail padding.
+pub fn align_samples(samples: &[i16], notes: &[MoonbaseNote], sample_rate: u32, tail_samples: usize) -> Vec<i16>
+{
+    let timing = nominal_timing(notes, sample_rate);
+    let detected = find_onsets(samples, sample_rate);
+    let mut anchors = match_onsets(&detected, &timing.onsets, 0, sample_rate);
+    if anchors.is_empty() && !detected.is_empty() && !timing.onsets.is_empty()
+    {
+        // nothing started near where it should, e.g. after a long lead-in,
+        // so take the first sound heard as the first passage
+        let drift = detected[0] as i64 - timing.onsets[0] as i64;
+        anchors = match_onsets(&detected, &timing.onsets, drift, sample_rate);
+    }
+    let threshold = sound_threshold(samples);
+    let tolerance = ms_to_samples(TOLERANCE_MS as i64, sample_rate);
+
+    let mut out = vec![];
+    // where the silence ahead of the next passage starts
+    let mut gap_start = 0;
+    for (i, &(actual, nominal)) in anchors.iter().enumerate()
+    {
+        let gap = &samples[gap_start..actual];
+        if (out.len() + gap.len()).abs_diff(nominal) <= tolerance
+        {
+            out.extend_from_slice(gap);
+        }
+        else
+        {
+            // keep the start of the gap, where the last passage dies away,
+            // and pad with silence if that's not enough. a passage that
+            // ran too long to leave any gap just starts late.
+            let keep = gap.len().min(nominal.saturating_sub(out.len()));
+            out.extend_from_slice(&gap[..keep]);
+            out.resize(out.len().max(nominal), 0);
+        }
+
+        // a passage runs to its last sound before the next one starts;
+        // the last one runs to the end of the render
+        gap_start = match anchors.get(i + 1)
+        {
+            Some(&(next, _)) => actual + last_above(&samples[actual..next], threshold),
+            None => samples.len(),
+        };
+        out.extend_from_slice(&samples[actual..gap_start]);
+    }
+
+    out.truncate(out.len().saturating_sub(tail_samples).max(timing.len));
+
+    // nothing was sung, or the render came up short
+    if out.len() < timing.len
+    {
+        out.resize(timing.len, 0);
+    }
+    out
+}
+
+#[cfg(test)]
+fn test_note(prefix: &str, dur_ms: i32) -> MoonbaseNote
+{
+    MoonbaseNote { prefix: prefix.to_string(), suffix: String::new(), dur_ms, tone_id: crate::types::ToneId(13) }
+}
+
+// a stand-in render: sound for notes, silence for rests, with each
+// duration scaled and some lead-in and tail added
+#[cfg(test)]
+fn drifting_render(notes: &[MoonbaseNote], scale: f64, lead_in: usize, tail: usize) -> Vec<i16>
+{
+    let mut samples = vec![0; lead_in];
+    for n in notes
+    {
+        let len = (n.dur_ms as f64 * scale * 11.025) as usize;
+        let rest = n.prefix == "_";
+        samples.extend((0..len).map(|i|
+        {
+            if rest { 0 } else { ((i as f64 * 0.25).sin() * 8000.0) as i16 }
+        }));
+    }
+    samples.extend(vec![0; tail]);
+    samples
+}
+
+#[test]
+fn onset_detection()
+{
+    let notes = [test_note("ah", 400), test_note("_", 200), test_note("ah", 400), test_note("oh", 400)];
+    let samples = drifting_render(&notes, 1.0, 300, 1000);
+
+    // the second note is sung straight on from the first, so only the
+    // passage starts are found
+    let onsets = find_onsets(&samples, 11025);
+    assert_eq!(onsets.len(), 2);
+    assert!(onsets[0].abs_diff(300) <= 1);
+    assert!(onsets[1].abs_diff(300 + 6615) <= 1);
+    assert!(sound_end(&samples).abs_diff(300 + 15435) <= 2);
+
+    let timing = nominal_timing(&notes, 11025);
+    assert_eq!(timing.onsets, vec![0, 6615]);
+    assert_eq!(timing.sound_end, 15435);
+    assert_eq!(timing.len, 15435);
+}
+
+#[test]
+fn drift_correction()
+{
+    let notes = [test_note("ah", 1000), test_note("_", 500), test_note("ah", 1000),
+        test_note("_", 500), test_note("ah", 1000), test_note("_", 1000)];
+    let timing = nominal_timing(&notes, 11025);
+
+    // the engine runs 6% slow or fast, after 40 ms of lead-in
+    for scale in [1.06, 0.94]
+    {
+        let samples = drifting_render(&notes, scale, 441, 5500);
+        let aligned = align_samples(&samples, &notes, 11025, 0);
+
+        let onsets = find_onsets(&aligned, 11025);
+        assert_eq!(onsets.len(), 3);
+        for (actual, nominal) in onsets.iter().zip(&timing.onsets)
+        {
+            assert!(actual.abs_diff(*nominal) <= 2, "{} vs {}", actual, nominal);
+        }
+        assert!(aligned.len() >= timing.len);
+
+        // only the silences move; what was sung comes through untouched
+        let sung = find_onsets(&samples, 11025);
+        let len = (1000.0 * scale * 11.025) as usize;
+        for (actual, original) in onsets.iter().zip(&sung)
+        {
+            assert_eq!(aligned[*actual..*actual + len], samples[*original..*original + len]);
+        }
+    }
+
+    // an exact render passes through untouched, short of its padding
+    let exact = drifting_render(&notes, 1.0, 0, 5500);
+    assert_eq!(align_samples(&exact, &notes, 11025, 0), exact);
+    assert_eq!(align_samples(&exact, &notes, 11025, 5500), exact[..timing.len]);
+
+    // a lead-in longer than the onsets are looked for
+    let late = drifting_render(&notes, 1.0, 3000, 5500);
+    let onsets = find_onsets(&align_samples(&late, &notes, 11025, 0), 11025);
+    assert_eq!(onsets.len(), 3);
+    for (actual, nominal) in onsets.iter().zip(&timing.onsets)
+    {
+        assert!(actual.abs_diff(*nominal) <= 2, "{} vs {}", actual, nominal);
+    }
+
+    // all rests
+    let rests = [test_note("_", 1000)];
+    assert_eq!(align_samples(&vec![0; 5500], &rests, 11025, 5500), vec![0; 11025]);
+}
@@ -3,19 +3,20 @@
 use glob::glob;
 
 use regolith::parser::print_error;
-use regolith::compiler::{compile, CompileInput};
+use regolith::compiler::{compile, CompileInput, CompileOptions};
 use std::path::Path;
 
 fn main()
 {
     let build_dir = Path::new("build/");
+    let options = CompileOptions::default();
 
     for entry in glob("examples/*.md").unwrap()
     {
         if let Ok(e) = entry
         {
             let input = CompileInput::Markdown(&e);
-            let res = compile(&input, build_dir);
+            let res = compile(&input, build_dir, &options);
             if let Err(r) = res
             {
                 print_error(&r);
@@ -0,0 +1,85 @@
+#![allow(warnings)]
+
+use regolith::lexer::lex_multiline_string;
+use regolith::midi::midi_to_regolith;
+use regolith::parser::print_error;
+use regolith::types::{Scale, Token};
+use argparse::{ArgumentParser, Store};
+use std::path::Path;
+
+fn parse_key(key: &str) -> Option<Scale>
+{
+    let tokens = lex_multiline_string(key).ok()?;
+    match tokens.first()
+    {
+        Some((_, Token::Scale(s))) => Some(s.clone()),
+        _ => None
+    }
+}
+
+fn main() -> Result<(), ()>
+{
+    let mut inpath = String::new();
+    let mut outpath = String::new();
+    let mut key = String::new();
+    let mut grid: u32 = 4;
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Converts a MIDI file into regolith source.");
+        ap.refer(&mut outpath)
+            .add_option(&["--output"], Store, "Output markdown file (default: input with .md extension)");
+        ap.refer(&mut key)
+            .add_option(&["--key"], Store, "Scale to write pitches against, e.g. CMAJOR");
+        ap.refer(&mut grid)
+            .add_option(&["--grid"], Store, "Quantization steps per beat (default 4)");
+        ap.refer(&mut inpath)
+            .add_argument("midi-file", Store, "Input MIDI file")
+            .required();
+        ap.parse_args_or_exit();
+    }
+
+    let inpath = Path::new(&inpath);
+    let outpath = if outpath.is_empty()
+    {
+        inpath.with_extension("md")
+    }
+    else
+    {
+        Path::new(&outpath).to_path_buf()
+    };
+
+    let scale = if key.is_empty()
+    {
+        None
+    }
+    else
+    {
+        match parse_key(&key)
+        {
+            Some(s) => Some(s),
+            None =>
+            {
+                println!("Invalid key \"{}\"", key);
+                return Err(());
+            }
+        }
+    };
+
+    let bytes = match std::fs::read(inpath)
+    {
+        Ok(b) => b,
+        Err(e) => { println!("Failed to read {}: {}", inpath.display(), e); return Err(()) }
+    };
+
+    let title = inpath.file_stem().and_then(|s| s.to_str()).unwrap_or("imported");
+
+    let res = midi_to_regolith(&bytes, title, scale.as_ref(), grid)
+        .and_then(|markdown| Ok(std::fs::write(&outpath, markdown)?));
+
+    match res
+    {
+        Ok(_) => { println!("Wrote {}", outpath.display()); Ok(()) },
+        Err(e) => { print_error(&e); Err(()) },
+    }
+}
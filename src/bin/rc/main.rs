@@ -1,23 +1,160 @@
 #![allow(warnings)]
 
-use regolith::compiler::{compile, CompileInput};
+use regolith::calibrate::{calibrate, save_timing};
+use regolith::codegen::{default_jobs, RenderOptions, DEFAULT_CHUNK_SIZE};
+use regolith::compiler::{compile, CompileInput, CompileOptions, Emit};
+use regolith::mix::{MasterOptions, Normalize};
 use regolith::parser::print_error;
-use argparse::{ArgumentParser, Store};
+use regolith::tts::{DectalkBackend, HttpBackend, StubBackend, TtsBackend, DEFAULT_TTS_RATE, DEFAULT_TTS_URL};
+use regolith::synth::{SynthBackend, SynthVoice};
+use argparse::{ArgumentParser, Collect, Store, StoreTrue};
 use std::path::Path;
 
+fn make_backend(name: &str, tts_url: &str, tts_rate: f64, say: &str, synth_voice: &str) -> Option<Box<dyn TtsBackend>>
+{
+    match name
+    {
+        "http"    => Some(Box::new(HttpBackend::with_rate(tts_url, tts_rate))),
+        "dectalk" => Some(Box::new(DectalkBackend::new(Path::new(say)))),
+        "stub"    => Some(Box::new(StubBackend)),
+        "synth"   =>
+        {
+            let voice = match synth_voice
+            {
+                "tone"    => SynthVoice::Tone,
+                "formant" => SynthVoice::Formant,
+                _         => return None
+            };
+            Some(Box::new(SynthBackend { voice }))
+        },
+        _         => None
+    }
+}
+
+// rc calibrate [backend options] build-dir
+fn calibrate_main(args: Vec<String>) -> Result<(), ()>
+{
+    let mut build_dir = String::new();
+    let mut backend = "http".to_string();
+    let mut tts_url = DEFAULT_TTS_URL.to_string();
+    let mut tts_rate = DEFAULT_TTS_RATE;
+    let mut say = "say".to_string();
+    let mut synth_voice = "formant".to_string();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Measure how a TTS backend pads its renders.");
+        ap.refer(&mut backend)
+            .add_option(&["--backend"], Store, "TTS backend: http, dectalk, synth or stub");
+        ap.refer(&mut tts_url)
+            .add_option(&["--tts-url"], Store, "Base URL of the HTTP TTS service");
+        ap.refer(&mut tts_rate)
+            .add_option(&["--tts-rate"], Store, "Requests per second to send the HTTP TTS service");
+        ap.refer(&mut say)
+            .add_option(&["--say"], Store, "Path to the DECtalk say executable");
+        ap.refer(&mut synth_voice)
+            .add_option(&["--synth-voice"], Store, "Voice for the synth backend: tone or formant");
+        ap.refer(&mut build_dir)
+            .add_argument("build-dir", Store, "Build directory whose cache the timing is saved in")
+            .required();
+        if let Err(code) = ap.parse(args, &mut std::io::stdout(), &mut std::io::stderr())
+        {
+            std::process::exit(code);
+        }
+    }
+
+    let backend = match make_backend(&backend, &tts_url, tts_rate, &say, &synth_voice)
+    {
+        Some(b) => b,
+        None =>
+        {
+            println!("Unknown backend \"{}\" or synth voice \"{}\"", backend, synth_voice);
+            return Err(());
+        }
+    };
+
+    // probes are always rendered afresh, never taken from the cache
+    let build_dir = Path::new(&build_dir);
+    let work_dir = build_dir.join("calibration");
+    let _ = std::fs::remove_dir_all(&work_dir);
+
+    let res = std::fs::create_dir_all(build_dir).map_err(|e| e.into())
+        .and_then(|_| calibrate(backend.as_ref(), &work_dir))
+        .and_then(|timing|
+        {
+            let path = save_timing(&build_dir.join("cache"), backend.as_ref(), &timing)?;
+            println!("{} ms per note, {} samples of tail; written to {}",
+                timing.note_bias_ms, timing.tail_samples, path.display());
+            Ok(())
+        });
+
+    match res
+    {
+        Ok(_) => Ok(()),
+        Err(e) => { print_error(&e); Err(()) },
+    }
+}
+
 fn main() -> Result<(), ()>
 {
+    let args : Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("calibrate")
+    {
+        let mut rest = vec![format!("{} calibrate", args[0])];
+        rest.extend_from_slice(&args[2..]);
+        return calibrate_main(rest);
+    }
+
     let mut inpath = String::new();
     let mut source = String::new();
     let mut build_dir = String::new();
+    let mut backend = "http".to_string();
+    let mut tts_url = DEFAULT_TTS_URL.to_string();
+    let mut tts_rate = DEFAULT_TTS_RATE;
+    let mut jobs = default_jobs();
+    let mut chunk_size = DEFAULT_CHUNK_SIZE;
+    let mut normalize = "lufs:-16".to_string();
+    let mut ceiling = MasterOptions::default().ceiling_db;
+    let mut solo : Vec<u32> = vec![];
+    let mut mute : Vec<u32> = vec![];
+    let mut stems = false;
+    let mut say = "say".to_string();
+    let mut synth_voice = "formant".to_string();
+    let mut emit = "wav".to_string();
 
     {
         let mut ap = ArgumentParser::new();
-        ap.set_description("Regolith compiler.");
+        ap.set_description("Regolith compiler. See also: rc calibrate --help");
         ap.refer(&mut inpath)
             .add_option(&["--path"], Store, "Input regolith file");
         ap.refer(&mut source)
             .add_option(&["--source"], Store, "Regolith source to parse");
+        ap.refer(&mut backend)
+            .add_option(&["--backend"], Store, "TTS backend: http, dectalk, synth or stub");
+        ap.refer(&mut tts_url)
+            .add_option(&["--tts-url"], Store, "Base URL of the HTTP TTS service");
+        ap.refer(&mut tts_rate)
+            .add_option(&["--tts-rate"], Store, "Requests per second to send the HTTP TTS service");
+        ap.refer(&mut jobs)
+            .add_option(&["-j", "--jobs"], Store, "Number of tracks to render at once");
+        ap.refer(&mut chunk_size)
+            .add_option(&["--chunk-size"], Store, "Longest moonbase string to send in one request; longer tracks are split");
+        ap.refer(&mut normalize)
+            .add_option(&["--normalize"], Store, "Song level: lufs:<LUFS>, peak:<dBFS> or off");
+        ap.refer(&mut ceiling)
+            .add_option(&["--ceiling"], Store, "Limiter ceiling in dBFS");
+        ap.refer(&mut solo)
+            .add_option(&["--solo"], Collect, "Render only this track; may be repeated");
+        ap.refer(&mut mute)
+            .add_option(&["--mute"], Collect, "Leave this track out of the render; may be repeated");
+        ap.refer(&mut stems)
+            .add_option(&["--stems"], StoreTrue, "Also write each track as a full-length WAV in build-dir/stems");
+        ap.refer(&mut say)
+            .add_option(&["--say"], Store, "Path to the DECtalk say executable");
+        ap.refer(&mut synth_voice)
+            .add_option(&["--synth-voice"], Store, "Voice for the synth backend: tone or formant");
+        ap.refer(&mut emit)
+            .add_option(&["--emit"], Store, "Output to produce: wav or midi");
         ap.refer(&mut build_dir)
             .add_argument("build-dir", Store, "Output build directory")
             .required();
@@ -26,13 +163,54 @@ fn main() -> Result<(), ()>
 
     let dir = Path::new(&build_dir);
 
+    let emit = match emit.as_str()
+    {
+        "wav"  => Emit::Wav,
+        "midi" => Emit::Midi,
+        _ =>
+        {
+            println!("Unknown emit mode \"{}\"", emit);
+            return Err(());
+        }
+    };
+
+    let normalize = match Normalize::parse(&normalize)
+    {
+        Some(n) => n,
+        None =>
+        {
+            println!("Unknown normalization \"{}\"", normalize);
+            return Err(());
+        }
+    };
+
+    let render = RenderOptions
+    {
+        jobs: jobs.max(1),
+        chunk_size,
+        master: MasterOptions { normalize, ceiling_db: ceiling },
+        solo,
+        mute,
+        stems
+    };
+
+    let options = match make_backend(&backend, &tts_url, tts_rate, &say, &synth_voice)
+    {
+        Some(b) => CompileOptions { backend: b, emit, render },
+        None =>
+        {
+            println!("Unknown backend \"{}\" or synth voice \"{}\"", backend, synth_voice);
+            return Err(());
+        }
+    };
+
     let res = if !inpath.is_empty()
     {
-        compile(&CompileInput::Markdown(Path::new(&inpath)), dir)
+        compile(&CompileInput::Markdown(Path::new(&inpath)), dir, &options)
     }
     else if !source.is_empty()
     {
-        compile(&CompileInput::StringLiteral(&source), dir)
+        compile(&CompileInput::StringLiteral(&source), dir, &options)
     }
     else
     {
@@ -0,0 +1,147 @@
+use crate::codegen::generate_moonbase_or_error;
+use crate::moonbase::{create_dir, Timing};
+use crate::tts::TtsBackend;
+use crate::types::{CompileError, CompileResult};
+use std::path::{Path, PathBuf};
+
+// where a backend's fitted timing lives, next to its cached renders
+pub fn timing_path(cache_dir: &Path, backend: &dyn TtsBackend) -> PathBuf
+{
+    cache_dir.join(backend.name()).join("timing.conf")
+}
+
+// the backend's calibrated timing, or the defaults if it was never calibrated
+pub fn load_timing(cache_dir: &Path, backend: &dyn TtsBackend) -> CompileResult<Timing>
+{
+    let path = timing_path(cache_dir, backend);
+    if !path.exists()
+    {
+        return Ok(Timing::default());
+    }
+    Timing::from_config(&std::fs::read_to_string(path)?)
+}
+
+pub fn save_timing(cache_dir: &Path, backend: &dyn TtsBackend, timing: &Timing) -> CompileResult<PathBuf>
+{
+    let path = timing_path(cache_dir, backend);
+    create_dir(cache_dir)?;
+    create_dir(&cache_dir.join(backend.name()))?;
+    std::fs::write(&path, format!("# fitted by rc calibrate\n{}", timing.to_config()))?;
+    Ok(path)
+}
+
+// a moonbase string asking for exactly nominal_ms of audio, sung notes
+// included at their face value
+struct Probe
+{
+    moonbase: String,
+    nominal_ms: i64,
+    sung: usize
+}
+
+// a spread of note counts, lengths, rests and tones, so the fit doesn't
+// lean on any one of them
+fn probes() -> Vec<Probe>
+{
+    let shapes : [(usize, i64, usize, u8); 9] = [
+        // notes, ms each, every nth is a rest, tone
+        (1, 500, 0, 13),
+        (2, 250, 0, 19),
+        (4, 1000, 0, 25),
+        (4, 200, 2, 7),
+        (8, 125, 0, 31),
+        (8, 400, 3, 13),
+        (16, 100, 4, 19),
+        (16, 250, 0, 10),
+        (32, 150, 5, 22),
+    ];
+
+    shapes.iter().map(|&(count, ms, rest_every, tone)|
+    {
+        let rest = |i: usize| rest_every > 0 && i % rest_every == rest_every - 1;
+        Probe
+        {
+            moonbase: (0..count).map(|i|
+            {
+                format!("[{}<{},{}>]", if rest(i) { "_" } else { "duw" }, ms, tone)
+            })
+            .collect(),
+            nominal_ms: count as i64 * ms,
+            sung: (0..count).filter(|i| !rest(*i)).count()
+        }
+    })
+    .collect()
+}
+
+// least squares line through (x, y) points, as (slope, intercept)
+fn fit_line(points: &[(f64, f64)]) -> Option<(f64, f64)>
+{
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let var_x : f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    if var_x == 0.0
+    {
+        return None;
+    }
+    let cov : f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let slope = cov / var_x;
+    Some((slope, mean_y - slope * mean_x))
+}
+
+// Renders every probe and fits the audio each produced beyond what it
+// asked for as a fixed cost per sung note plus a fixed tail.
+pub fn calibrate(backend: &dyn TtsBackend, work_dir: &Path) -> CompileResult<Timing>
+{
+    create_dir(work_dir)?;
+
+    let mut sample_rate = 0;
+    let mut points = vec![];
+    for probe in probes()
+    {
+        let path = generate_moonbase_or_error(backend, &probe.moonbase, work_dir)?;
+        let reader = hound::WavReader::open(&path)?;
+        sample_rate = reader.spec().sample_rate;
+
+        let nominal = probe.nominal_ms as f64 * sample_rate as f64 / 1000.0;
+        let extra = reader.duration() as f64 - nominal;
+        println!("{:>3} notes ({:>2} sung), {:>5} ms asked, {:>+8.0} samples extra",
+            probe.moonbase.matches('[').count(), probe.sung, probe.nominal_ms, extra);
+        points.push((probe.sung as f64, extra));
+    }
+
+    let (per_note, tail) = fit_line(&points)
+        .ok_or_else(|| CompileError::Generic("calibration probes are degenerate".to_string()))?;
+
+    Ok(Timing
+    {
+        note_bias_ms: (per_note * 1000.0 / sample_rate as f64).round() as i32,
+        tail_samples: tail.round().max(0.0) as usize
+    })
+}
+
+#[test]
+fn line_fitting()
+{
+    let points = [(1.0, 7.0), (2.0, 9.0), (4.0, 13.0)];
+    let (slope, intercept) = fit_line(&points).unwrap();
+    assert!((slope - 2.0).abs() < 1e-9 && (intercept - 5.0).abs() < 1e-9);
+    assert_eq!(fit_line(&[(3.0, 1.0), (3.0, 2.0)]), None);
+}
+
+#[test]
+fn stub_calibration()
+{
+    // the stub pads like the real engine is assumed to, so calibrating it
+    // should land on the built-in constants
+    let dir = std::env::temp_dir().join("regolith-stub-calibration");
+    let _ = std::fs::remove_dir_all(&dir);
+    let timing = calibrate(&crate::tts::StubBackend, &dir).unwrap();
+    assert_eq!(timing.note_bias_ms, Timing::default().note_bias_ms);
+    assert!(timing.tail_samples.abs_diff(Timing::default().tail_samples) < 10);
+
+    let cache_dir = dir.join("cache");
+    assert_eq!(load_timing(&cache_dir, &crate::tts::StubBackend).unwrap(), Timing::default());
+    save_timing(&cache_dir, &crate::tts::StubBackend, &timing).unwrap();
+    assert_eq!(load_timing(&cache_dir, &crate::tts::StubBackend).unwrap(), timing);
+}
@@ -2,38 +2,452 @@ use std::collections::HashSet;
 
 use hound::WavSpec;
 use reqwest::StatusCode;
-use crate::semantics::Composition;
-use crate::types::{CompileError, CompileResult, NoteDecl};
-use crate::moonbase::{create_dir, generate_moonbase, to_moonbase_str, MoonbaseError, MoonbaseNote};
-use fraction::{Fraction, ToPrimitive};
+use crate::semantics::{fan_out_chords, Composition};
+use crate::types::{CompileError, CompileResult, DynamicLevel, DynamicMap, Measure, NoteDecl, TempoMap};
+use crate::moonbase::{create_dir, generate_moonbase, speaker_prefix, to_moonbase_str, MoonbaseError, MoonbaseNote, Timing};
+use crate::align::{align_samples, ms_to_samples};
+use crate::mix::{master, to_float, to_pcm16, MasterOptions};
+use crate::tts::TtsBackend;
+use fraction::Fraction;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
 
-fn bpm_to_millis(beats: &Fraction, bpm: u16) -> Option<i32>
+fn dynamic_to_decibels(level: &DynamicLevel) -> f32
 {
-    let milliseconds_per_beat = Fraction::new(60000 as u64, bpm);
-    Some((beats * milliseconds_per_beat).to_f64()?.round() as i32)
+    // fortissimo is rendered at the engine's native level; everything
+    // else is attenuated from there.
+    match level
+    {
+        DynamicLevel::Pianissimo => -18.0,
+        DynamicLevel::Piano      => -12.0,
+        DynamicLevel::Mezzopiano => -8.0,
+        DynamicLevel::Mezzoforte => -5.0,
+        DynamicLevel::Forte      => -2.5,
+        DynamicLevel::Fortissimo => 0.0,
+    }
+}
+
+fn decibels_to_gain(db: f32) -> f32
+{
+    10.0f32.powf(db / 20.0)
+}
+
+#[test]
+fn dynamic_gains()
+{
+    let levels = [
+        DynamicLevel::Pianissimo,
+        DynamicLevel::Piano,
+        DynamicLevel::Mezzopiano,
+        DynamicLevel::Mezzoforte,
+        DynamicLevel::Forte,
+        DynamicLevel::Fortissimo,
+    ];
+
+    let gains : Vec<f32> = levels.iter()
+        .map(|l| decibels_to_gain(dynamic_to_decibels(l))).collect();
+
+    assert!(gains.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(gains[5], 1.0);
+    assert!((gains[1] - 0.251).abs() < 0.001);
+}
+
+#[test]
+fn tied_notes_merge()
+{
+    let comp = crate::semantics::semantics_of("4/4 [1] | . . . ah-n~ | ah-t:2 - - |").unwrap();
+
+    let notes = to_moonbase_notes(&TempoMap::constant(60), &comp.sections[0].tracks[&1]);
+    let durations : Vec<i32> = notes.iter().map(|n| n.dur_ms).collect();
+    assert_eq!(durations, vec![1000, 1000, 1000, 3000, 1000, 1000]);
+    assert_eq!(notes[3].prefix, "ah");
+    assert_eq!(notes[3].suffix, "t");
+}
+
+#[test]
+fn ritardando_durations()
+{
+    let comp = crate::semantics::semantics_of("60BPM 4/4 [1] | . . . . | RIT . . . . | 30BPM . . . . |").unwrap();
+
+    let notes = to_moonbase_notes(&comp.sections[0].tempo_map, &comp.sections[0].tracks[&1]);
+    let durations : Vec<i32> = notes.iter().map(|n| n.dur_ms).collect();
+    assert_eq!(durations, vec![1000, 1000, 1000, 1000, 1125, 1375, 1625, 1875, 2000, 2000, 2000, 2000]);
 }
 
-fn to_moonbase_note(bpm: u16, n: &NoteDecl) -> MoonbaseNote
+// A track's gain (in dB) at points in time. Where `ramp` is set the gain
+// moves linearly to the next point; otherwise it holds until then.
+#[derive(Debug, Clone, PartialEq)]
+struct GainPoint
+{
+    ms: f64,
+    db: f32,
+    ramp: bool
+}
+
+fn gain_envelope(dynamics: &DynamicMap, tempo: &TempoMap) -> Vec<GainPoint>
+{
+    dynamics.segments().iter().map(|s| GainPoint
+    {
+        ms: tempo.millis_at(&s.start),
+        db: dynamic_to_decibels(&s.from),
+        ramp: s.from != s.to
+    })
+    .collect()
+}
+
+fn envelope_gain_at(envelope: &[GainPoint], ms: f64) -> f32
+{
+    let i = envelope.iter().rposition(|p| p.ms <= ms).unwrap_or(0);
+    let p = &envelope[i];
+    let db = match envelope.get(i + 1)
+    {
+        Some(next) if p.ramp && next.ms > p.ms =>
+            p.db + (next.db - p.db) * ((ms - p.ms) / (next.ms - p.ms)) as f32,
+        _ => p.db,
+    };
+    decibels_to_gain(db)
+}
+
+#[test]
+fn hairpin_envelope()
+{
+    let comp = crate::semantics::semantics_of("60BPM PIANO 4/4 [1] | . CRESC . . FORTE . |").unwrap();
+    let section = &comp.sections[0];
+
+    let envelope = gain_envelope(&section.dynamics[&1], &section.tempo_map);
+    let db_at = |ms: f64| 20.0 * envelope_gain_at(&envelope, ms).log10();
+
+    assert!((db_at(500.0) + 12.0).abs() < 0.01);
+    assert!((db_at(1000.0) + 12.0).abs() < 0.01);
+    assert!((db_at(2000.0) + 7.25).abs() < 0.01);
+    assert!((db_at(3000.0) + 2.5).abs() < 0.01);
+    assert!((db_at(9000.0) + 2.5).abs() < 0.01);
+}
+
+// how much of a track goes to the left and right channels. the pan law
+// is constant power, so a track sounds as loud wherever it's placed and
+// a centered one measures the same as it would in mono.
+fn channel_gains(pan: f32, gain_db: f32) -> [f32; 2]
+{
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    let gain = decibels_to_gain(gain_db);
+    [angle.cos() * gain, angle.sin() * gain]
+}
+
+fn apply_envelope(samples: &mut [f32], envelope: &[GainPoint], sample_rate: u32)
+{
+    for (i, s) in samples.iter_mut().enumerate()
+    {
+        *s *= envelope_gain_at(envelope, i as f64 * 1000.0 / sample_rate as f64);
+    }
+}
+
+fn to_moonbase_note(tempo: &TempoMap, start: &Fraction, n: &NoteDecl) -> MoonbaseNote
 {
     MoonbaseNote
     {
         prefix: n.note.prefix.clone(),
         suffix: n.note.suffix.clone(),
-        dur_ms: bpm_to_millis(&n.note.beats, bpm).unwrap_or(0),
+        dur_ms: tempo.duration_ms(start, &n.note.beats),
         tone_id: n.tone_id
     }
 }
 
-fn generate_moonbase_or_error(moonbase: &str, tmp_dir: &Path) -> CompileResult<PathBuf>
+// tied notes are sung as a single syllable spanning all of their beats
+fn to_moonbase_notes(tempo: &TempoMap, measures: &[Measure]) -> Vec<MoonbaseNote>
+{
+    to_moonbase_notes_with_breaks(tempo, measures).0
+}
+
+// also returns the indices of notes a long track may be split before:
+// the first note of each measure, unless a tie runs into it, and any
+// note following a rest
+fn to_moonbase_notes_with_breaks(tempo: &TempoMap, measures: &[Measure]) -> (Vec<MoonbaseNote>, Vec<usize>)
+{
+    let mut notes: Vec<MoonbaseNote> = vec![];
+    let mut breaks = vec![];
+    let mut tied = false;
+    let mut cursor = Fraction::from(0);
+    for measure in measures
+    {
+        for (i, n) in measure.notes.iter().enumerate()
+        {
+            let mbn = to_moonbase_note(tempo, &cursor, n);
+            cursor += n.note.beats;
+            match notes.last_mut()
+            {
+                Some(prev) if tied =>
+                {
+                    prev.dur_ms += mbn.dur_ms;
+                    prev.suffix = mbn.suffix;
+                },
+                Some(prev) =>
+                {
+                    if i == 0 || prev.prefix == "_"
+                    {
+                        breaks.push(notes.len());
+                    }
+                    notes.push(mbn);
+                },
+                None => notes.push(mbn),
+            }
+            tied = n.note.tie;
+        }
+    }
+    (notes, breaks)
+}
+
+// bytes of moonbase text sent in one request; the service rejects much
+// longer strings, and tracks over this are split into several renders
+pub const DEFAULT_CHUNK_SIZE: usize = 4000;
+
+pub struct RenderOptions
+{
+    // tracks rendered at once; the backend still rate limits its requests
+    pub jobs: usize,
+    // longest moonbase string sent in one request
+    pub chunk_size: usize,
+    pub master: MasterOptions,
+    // when any tracks are soloed, only those are heard
+    pub solo: Vec<u32>,
+    pub mute: Vec<u32>,
+    // also write each track on its own, as long as the song
+    pub stems: bool
+}
+
+impl RenderOptions
+{
+    pub fn is_audible(&self, track: u32) -> bool
+    {
+        !self.mute.contains(&track) && (self.solo.is_empty() || self.solo.contains(&track))
+    }
+}
+
+pub fn default_jobs() -> usize
+{
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4)
+}
+
+impl Default for RenderOptions
 {
-    match generate_moonbase(moonbase, tmp_dir)
+    fn default() -> Self
+    {
+        RenderOptions
+        {
+            jobs: default_jobs(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            master: MasterOptions::default(),
+            solo: vec![],
+            mute: vec![],
+            stems: false
+        }
+    }
+}
+
+// packs a track's notes into moonbase strings of at most `limit` bytes,
+// cutting only at breaks. a stretch between two breaks that is longer
+// than the limit becomes a chunk of its own. each chunk comes with its
+// start in ms.
+fn chunk_moonbase(notes: &[MoonbaseNote], breaks: &[usize], limit: usize, timing: &Timing) -> Vec<(i32, String)>
+{
+    let bounds : Vec<usize> = std::iter::once(0).chain(breaks.iter().cloned())
+        .chain(std::iter::once(notes.len())).collect();
+
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    let mut start = 0;
+    let mut ms = 0;
+    for w in bounds.windows(2)
+    {
+        let piece : String = notes[w[0]..w[1]].iter().map(|n| to_moonbase_str(n, timing)).collect();
+        if !chunk.is_empty() && chunk.len() + piece.len() > limit
+        {
+            chunks.push((start, std::mem::take(&mut chunk)));
+            start = ms;
+        }
+        chunk += &piece;
+        ms += notes[w[0]..w[1]].iter().map(|n| n.dur_ms).sum::<i32>();
+    }
+    chunks.push((start, chunk));
+    chunks
+}
+
+#[test]
+fn track_chunking()
+{
+    let comp = crate::semantics::semantics_of(
+        "60BPM 4/4 [1] | a b c d | e f - g | h i j k~ | k l m n |").unwrap();
+
+    let (notes, breaks) = to_moonbase_notes_with_breaks(&TempoMap::constant(60), &comp.sections[0].tracks[&1]);
+    // the tie into the last measure keeps it attached to the third
+    assert_eq!(breaks, vec![4, 7, 8]);
+    assert_eq!(notes.len(), 15);
+
+    let timing = Timing::default();
+    let whole : String = notes.iter().map(|n| to_moonbase_str(n, &timing)).collect();
+    assert_eq!(chunk_moonbase(&notes, &breaks, whole.len(), &timing), vec![(0, whole.clone())]);
+
+    let chunks = chunk_moonbase(&notes, &breaks, 60, &timing);
+    assert!(chunks.len() > 1);
+    assert_eq!(chunks.iter().map(|(_, c)| c.as_str()).collect::<String>(), whole);
+    let starts : Vec<i32> = chunk_moonbase(&notes, &breaks, 1, &timing).iter().map(|(ms, _)| *ms).collect();
+    assert_eq!(starts, vec![0, 4000, 7000, 8000]);
+}
+
+// stands in for a service that refuses long strings
+#[cfg(test)]
+struct LimitedBackend(usize);
+
+#[cfg(test)]
+impl TtsBackend for LimitedBackend
+{
+    fn name(&self) -> &str
+    {
+        "limited"
+    }
+
+    fn render(&self, moonbase: &str, outpath: &Path) -> crate::moonbase::MoonbaseResult<()>
+    {
+        if moonbase.len() > self.0
+        {
+            return Err(MoonbaseError::TooLarge);
+        }
+        crate::tts::StubBackend.render(moonbase, outpath)
+    }
+}
+
+#[test]
+fn chunked_rendering()
+{
+    let source = "60BPM 4/4 [1] | a b c d | e f - g | h i j k~ | k l m n |\n[2] | ah:4 | ah:4 | ah:4 | ah:4 |";
+    let comp = crate::semantics::semantics_of(source).unwrap();
+
+    let root = std::env::temp_dir().join("regolith-chunked-rendering");
+    let _ = std::fs::remove_dir_all(&root);
+    let render = |backend: &dyn TtsBackend, chunk_size: usize|
+    {
+        // a fresh cache each time, so every chunk reaches the backend
+        let build_dir = root.join(format!("{}-{}", backend.name(), chunk_size));
+        let _ = std::fs::remove_dir_all(&build_dir);
+        std::fs::create_dir_all(&build_dir).unwrap();
+        let options = RenderOptions { jobs: 2, chunk_size, ..RenderOptions::default() };
+        generate_mb_code(&comp, backend, &Timing::default(), &build_dir.join("cache"), &build_dir, &options)?;
+        Ok::<Vec<u8>, CompileError>(std::fs::read(build_dir.join("section-0-track-1.wav")).unwrap())
+    };
+
+    // chunks are stitched back sample for sample
+    let whole = render(&crate::tts::StubBackend, DEFAULT_CHUNK_SIZE).unwrap();
+    assert_eq!(render(&crate::tts::StubBackend, 50).unwrap(), whole);
+    assert_eq!(render(&LimitedBackend(100), DEFAULT_CHUNK_SIZE).unwrap(), whole);
+
+    // a single syllable can't be split any further
+    assert!(matches!(render(&LimitedBackend(10), DEFAULT_CHUNK_SIZE), Err(CompileError::TrackTooLarge)));
+}
+
+// renders a unit to dst, splitting it into smaller chunks for as long as
+// the backend turns them down as too large. returns the number of chunks.
+fn render_unit(backend: &dyn TtsBackend, timing: &Timing, unit: &RenderUnit, cache_dir: &Path, dst: &Path,
+    chunk_size: usize) -> CompileResult<usize>
+{
+    // every chunk starts over in the unit's voice
+    let mut limit = chunk_size.saturating_sub(unit.speaker.len()).max(1);
+    loop
+    {
+        let chunks = chunk_moonbase(&unit.notes, &unit.breaks, limit, timing);
+        let rendered = chunks.iter()
+            .map(|(_, moonbase)| generate_moonbase_or_error(backend, &format!("{}{}", unit.speaker, moonbase), cache_dir))
+            .collect::<CompileResult<Vec<PathBuf>>>();
+
+        match rendered
+        {
+            Err(CompileError::TrackTooLarge) =>
+            {
+                let largest = chunks.iter().map(|(_, c)| c.len()).max().unwrap_or(0);
+                if chunk_moonbase(&unit.notes, &unit.breaks, largest / 2, timing).len() == chunks.len()
+                {
+                    return Err(CompileError::TrackTooLarge);
+                }
+                limit = largest / 2;
+            },
+            Err(e) => return Err(e),
+            Ok(paths) if paths.len() == 1 =>
+            {
+                std::fs::copy(&paths[0], dst)?;
+                return Ok(1);
+            },
+            Ok(paths) =>
+            {
+                let placed : Vec<(i32, PathBuf)> = chunks.iter().map(|(ms, _)| *ms).zip(paths).collect();
+                stitch_chunks(&placed, dst)?;
+                return Ok(placed.len());
+            },
+        }
+    }
+}
+
+// mixes audio together, each part placed where it starts in the score
+// (in ms) rather than where the previous one happened to end, so trailing
+// silence and releases overlap whatever follows
+fn place_at_offsets(parts: &[(i32, Vec<f32>)], channels: u16, sample_rate: u32) -> Vec<f32>
+{
+    let offsets : Vec<usize> = parts.iter()
+        .map(|(ms, _)| ms_to_samples(*ms as i64, sample_rate) * channels as usize).collect();
+    let len = offsets.iter().zip(parts).map(|(o, (_, s))| o + s.len()).max().unwrap_or(0);
+    let mut mix = vec![0.0; len];
+    for (offset, (_, s)) in offsets.iter().zip(parts)
+    {
+        for (i, x) in s.iter().enumerate()
+        {
+            mix[offset + i] += x;
+        }
+    }
+    mix
+}
+
+// puts a track's chunk renders back together, in the engine's own format
+fn stitch_chunks(chunks: &[(i32, PathBuf)], out: &Path) -> CompileResult<()>
+{
+    let paths : Vec<PathBuf> = chunks.iter().map(|(_, p)| p.clone()).collect();
+    let (samples, spec) = load_float_samples(&paths)?;
+    let parts : Vec<(i32, Vec<f32>)> = chunks.iter().map(|(ms, _)| *ms).zip(samples).collect();
+    let spec = WavSpec { bits_per_sample: 16, sample_format: hound::SampleFormat::Int, ..spec };
+    write_samples(&to_pcm16(&place_at_offsets(&parts, spec.channels, spec.sample_rate)), out, &spec)
+}
+
+// joins the sections into a song at least total_ms long, then masters it
+// down to 16 bits. returns the song's length in frames.
+fn master_song(sections: &[(i32, PathBuf)], total_ms: i32, out: &Path, options: &MasterOptions) -> CompileResult<usize>
+{
+    let paths : Vec<PathBuf> = sections.iter().map(|(_, p)| p.clone()).collect();
+    let (samples, spec) = load_float_samples(&paths)?;
+    let parts : Vec<(i32, Vec<f32>)> = sections.iter().map(|(ms, _)| *ms).zip(samples).collect();
+
+    let mut song = place_at_offsets(&parts, spec.channels, spec.sample_rate);
+    let len = ms_to_samples(total_ms as i64, spec.sample_rate) * spec.channels as usize;
+    if song.len() < len
+    {
+        song.resize(len, 0.0);
+    }
+    master(&mut song, spec.channels as usize, spec.sample_rate, options);
+
+    let spec = WavSpec { bits_per_sample: 16, sample_format: hound::SampleFormat::Int, ..spec };
+    write_samples(&to_pcm16(&song), out, &spec)?;
+    Ok(song.len() / spec.channels as usize)
+}
+
+pub fn generate_moonbase_or_error(backend: &dyn TtsBackend, moonbase: &str, tmp_dir: &Path) -> CompileResult<PathBuf>
+{
+    match generate_moonbase(backend, moonbase, tmp_dir)
     {
         Ok(path) => Ok(path),
         Err(e) => match e
         {
             MoonbaseError::Generic => return Err(CompileError::Generic("Woopsies!".to_string())),
             MoonbaseError::FileError(fe) => return Err(CompileError::FileError(fe)),
+            MoonbaseError::EngineError(msg) => return Err(CompileError::EngineError(msg)),
+            MoonbaseError::TooLarge => return Err(CompileError::TrackTooLarge),
             MoonbaseError::NetworkError(ne) =>
             {
                 match ne.status()
@@ -73,6 +487,26 @@ fn load_samples(tracks: &[PathBuf]) -> CompileResult<(Vec<Vec<i16>>, hound::WavS
     Ok((samples, spec.unwrap()))
 }
 
+// reads 16-bit or float renders alike, as floats in [-1, 1)
+fn load_float_samples(tracks: &[PathBuf]) -> CompileResult<(Vec<Vec<f32>>, hound::WavSpec)>
+{
+    let mut spec = None;
+    let samples = tracks.iter().map(|p: &PathBuf|
+    {
+        let mut reader = hound::WavReader::open(p)?;
+        let s = reader.spec();
+        spec.get_or_insert(s);
+        match s.sample_format
+        {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>(),
+            hound::SampleFormat::Int => reader.samples::<i16>().collect::<Result<Vec<_>, _>>().map(|v| to_float(&v)),
+        }
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((samples, spec.unwrap()))
+}
+
 fn write_samples(samples: &Vec<i16>, out: &Path, spec: &WavSpec) -> CompileResult<()>
 {
     let mut writer = hound::WavWriter::create(out, *spec)?;
@@ -83,83 +517,493 @@ fn write_samples(samples: &Vec<i16>, out: &Path, spec: &WavSpec) -> CompileResul
     Ok(())
 }
 
-fn overlay_tracks(tracks: &[PathBuf], out: &Path) -> CompileResult<()>
+fn write_float_samples(samples: &[f32], out: &Path, spec: &WavSpec) -> CompileResult<()>
 {
-    let (samples, spec) = load_samples(tracks)?;
-    let len: usize = samples.iter().map(|s| s.len()).max().unwrap();
-    let sum : Vec<i16> = (0..len).map(|i: usize| (0..tracks.len()).map(|j| samples[j].get(i).unwrap_or(&0)).sum()).collect();
-    write_samples(&sum, out, &spec)?;
+    let mut writer = hound::WavWriter::create(out, *spec)?;
+    for s in samples
+    {
+        writer.write_sample(*s)?;
+    }
+    writer.finalize()?;
     Ok(())
 }
 
-fn append_tracks(tracks: &[PathBuf], out: &Path) -> CompileResult<()>
+// a mono render, with its dynamics and its left and right gains
+type TrackMix = (PathBuf, Vec<GainPoint>, [f32; 2]);
+
+// sections are mixed to stereo and kept in floating point, so no number
+// of voices can overflow before the song is mastered
+fn overlay_tracks(tracks: &[TrackMix], out: &Path) -> CompileResult<()>
 {
-    let (samples, spec) = load_samples(tracks)?;
-    let concat : Vec<i16> = samples.into_iter().map(|v|
+    let (sum, spec) = mix_tracks(tracks)?;
+    write_float_samples(&sum, out, &spec)
+}
+
+fn mix_tracks(tracks: &[TrackMix]) -> CompileResult<(Vec<f32>, WavSpec)>
+{
+    let paths : Vec<PathBuf> = tracks.iter().map(|(p, _, _)| p.clone()).collect();
+    let (mut samples, spec) = load_float_samples(&paths)?;
+    for (s, (_, envelope, _)) in samples.iter_mut().zip(tracks)
     {
-        drop_last_n_samples(&v, 5500)
-    }).flatten().collect();
-    write_samples(&concat, out, &spec)?;
-    Ok(())
+        apply_envelope(s, envelope, spec.sample_rate);
+    }
+    let len: usize = samples.iter().map(|s| s.len()).max().unwrap();
+    let mut sum = vec![0.0f32; len * 2];
+    for (s, (_, _, [left, right])) in samples.iter().zip(tracks)
+    {
+        sum.chunks_mut(2).zip(s).for_each(|(frame, x)|
+        {
+            frame[0] += x * left;
+            frame[1] += x * right;
+        });
+    }
+
+    let spec = WavSpec { channels: 2, bits_per_sample: 32, sample_format: hound::SampleFormat::Float, ..spec };
+    Ok((sum, spec))
+}
+
+#[test]
+fn loud_overlay()
+{
+    let dir = std::env::temp_dir().join("regolith-loud-overlay");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // three voices near full scale, all peaking at once
+    let spec = WavSpec { channels: 1, sample_rate: 11025, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let tracks : Vec<TrackMix> = (0..3).map(|i|
+    {
+        let path = dir.join(format!("voice-{}.wav", i));
+        let samples : Vec<i16> = (0..11025).map(|j| if j % 100 < 50 { 30000 } else { -30000 }).collect();
+        write_samples(&samples, &path, &spec).unwrap();
+        (path, vec![GainPoint { ms: 0.0, db: 0.0, ramp: false }], [1.0, 1.0])
+    })
+    .collect();
+
+    // frames interleave left and right
+    let section = dir.join("section.wav");
+    overlay_tracks(&tracks, &section).unwrap();
+    let (mixed, _) = load_float_samples(&[section.clone()]).unwrap();
+    assert!((mixed[0][0] - 90000.0 / 32768.0).abs() < 1e-4);
+    assert!((mixed[0][101] + 90000.0 / 32768.0).abs() < 1e-4);
+
+    // mastering brings it back under the ceiling without wrapping around
+    let song = dir.join("song.wav");
+    master_song(&[(0, section)], 0, &song, &MasterOptions::default()).unwrap();
+    let (samples, spec) = load_samples(&[song]).unwrap();
+    assert_eq!(spec.bits_per_sample, 16);
+    let ceiling = (crate::mix::db_to_gain(-1.0) * 32768.0) as i16;
+    assert!(samples[0].iter().all(|s| s.abs() <= ceiling + 1));
+    assert!(samples[0][0] > 0 && samples[0][101] < 0);
+}
+
+#[test]
+fn stereo_panning()
+{
+    let dir = std::env::temp_dir().join("regolith-stereo-panning");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // centered tracks keep their power, split evenly
+    let [left, right] = channel_gains(0.0, 0.0);
+    assert!((left - right).abs() < 1e-6 && (left * left + right * right - 1.0).abs() < 1e-6);
+    let [left, right] = channel_gains(0.5, -6.0);
+    assert!(right > left && ((left * left + right * right).sqrt() - decibels_to_gain(-6.0)).abs() < 1e-6);
+
+    let spec = WavSpec { channels: 1, sample_rate: 11025, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let flat = vec![GainPoint { ms: 0.0, db: 0.0, ramp: false }];
+    let (low, high) = (dir.join("low.wav"), dir.join("high.wav"));
+    write_samples(&vec![8192; 100], &low, &spec).unwrap();
+    write_samples(&vec![-16384; 100], &high, &spec).unwrap();
+
+    // one voice hard left, the other hard right and 6 dB down
+    let section = dir.join("section.wav");
+    overlay_tracks(&[(low, flat.clone(), channel_gains(-1.0, 0.0)), (high, flat, channel_gains(1.0, -6.0))],
+        &section).unwrap();
+    let (mixed, spec) = load_float_samples(&[section]).unwrap();
+    assert_eq!(spec.channels, 2);
+    assert_eq!(mixed[0].len(), 200);
+    assert!(mixed[0].chunks(2).all(|f| (f[0] - 0.25).abs() < 1e-6 && (f[1] + 0.25).abs() < 1e-3));
+}
+
+// moves a rendered track onto the timing its notes ask for
+fn align_track(path: &Path, notes: &[MoonbaseNote], timing: &Timing) -> CompileResult<()>
+{
+    let (samples, spec) = load_samples(&[path.to_path_buf()])?;
+    let aligned = align_samples(&samples[0], notes, spec.sample_rate, timing.tail_samples);
+    write_samples(&aligned, path, &spec)
 }
 
-fn drop_last_n_samples(sample: &Vec<i16>, n: usize) -> Vec<i16>
+// one track (or chord voice) of one section, ready to send to the engine
+struct RenderUnit
 {
-    let last = sample.len() - n;
-    sample[0..last].to_vec()
+    section: u32,
+    track: u32,
+    name: String,
+    // the engine's speaker switch, sent ahead of the notes
+    speaker: String,
+    notes: Vec<MoonbaseNote>,
+    breaks: Vec<usize>,
+    envelope: Vec<GainPoint>,
+    channel_gains: [f32; 2]
 }
 
-pub fn generate_mb_code(comp: &Composition, cache_dir: &Path, build_dir: &Path) -> CompileResult<()>
+// runs `work` over every item on up to `jobs` threads, returning results
+// in item order. once an item fails, workers stop picking up new ones.
+fn run_pool<T: Sync, R: Send>(items: &[T], jobs: usize,
+    work: &(dyn Fn(&T) -> CompileResult<R> + Sync)) -> CompileResult<Vec<R>>
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results : Vec<Mutex<Option<CompileResult<R>>>> = items.iter().map(|_| Mutex::new(None)).collect();
+
+    std::thread::scope(|scope|
+    {
+        for _ in 0..jobs.clamp(1, items.len().max(1))
+        {
+            scope.spawn(||
+            {
+                loop
+                {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= items.len() || failed.load(Ordering::SeqCst)
+                    {
+                        break;
+                    }
+                    let res = work(&items[i]);
+                    if res.is_err()
+                    {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    *results[i].lock().unwrap() = Some(res);
+                }
+            });
+        }
+    });
+
+    // skipped items leave gaps, but then some earlier item has failed
+    results.into_iter().filter_map(|r| r.into_inner().unwrap()).collect()
+}
+
+#[test]
+fn pool_ordering()
+{
+    let items : Vec<u64> = (0..50).collect();
+    let squares = run_pool(&items, 8, &|i|
+    {
+        std::thread::sleep(std::time::Duration::from_millis(50 - i));
+        Ok(i * i)
+    });
+    assert_eq!(squares.unwrap(), items.iter().map(|i| i * i).collect::<Vec<_>>());
+
+    let res = run_pool(&items, 4, &|i|
+    {
+        if *i == 7 { Err(CompileError::TrackTooLarge) } else { Ok(*i) }
+    });
+    assert!(matches!(res, Err(CompileError::TrackTooLarge)));
+}
+
+pub fn generate_mb_code(comp: &Composition, backend: &dyn TtsBackend, timing: &Timing, cache_dir: &Path,
+    build_dir: &Path, options: &RenderOptions) -> CompileResult<()>
 {
     let text_dir = build_dir.join("mb_text");
     create_dir(&text_dir)?;
 
     let song_out = build_dir.join("song.wav");
 
-    let section_wavs = comp.sections.iter().filter(|s|
+    let sections : Vec<_> = comp.sections.iter().filter(|s| !s.tracks.is_empty()).collect();
+
+    let mut units = vec![];
+    for section in &sections
     {
-        !s.tracks.is_empty()
-    })
-    .map(|section|
+        for (track_id, measures) in &section.tracks
+        {
+            let envelope = gain_envelope(&section.dynamics[track_id], &section.tempo_map);
+            let attributes = &section.track_attributes[track_id];
+            let gains = channel_gains(attributes.pan.unwrap_or(0.0), attributes.gain_db.unwrap_or(0.0));
+            let speaker = speaker_prefix(section.voices.get(track_id).cloned());
+
+            // renders of a declared part are named after it
+            let stem = match section.parts.get(track_id)
+            {
+                Some(part) => format!("section-{}-part-{}", section.id, part),
+                None => format!("section-{}-track-{}", section.id, track_id),
+            };
+
+            // each chord voice is rendered on its own and overlaid with
+            // the other tracks
+            for (voice, measures) in fan_out_chords(measures).iter().enumerate()
+            {
+                let name = if voice == 0
+                {
+                    stem.clone()
+                }
+                else
+                {
+                    format!("{}-voice-{}", stem, voice)
+                };
+
+                let (notes, breaks) = to_moonbase_notes_with_breaks(&section.tempo_map, measures);
+                let moonbase: String = std::iter::once(speaker.clone())
+                    .chain(notes.iter().map(|n| to_moonbase_str(n, timing))).collect();
+
+                std::fs::write(text_dir.join(format!("{}.txt", name)), &moonbase)?;
+
+                units.push(RenderUnit
+                {
+                    section: section.id,
+                    track: *track_id,
+                    name,
+                    speaker: speaker.clone(),
+                    notes,
+                    breaks,
+                    envelope: envelope.clone(),
+                    channel_gains: gains
+                });
+            }
+        }
+    }
+
+    // muted tracks aren't rendered at all, but still take up their time
+    let audible : Vec<&RenderUnit> = units.iter().filter(|unit| options.is_audible(unit.track)).collect();
+    if audible.is_empty()
     {
+        return Err(CompileError::Generic("every track is muted".to_string()));
+    }
+
+    let done = AtomicUsize::new(0);
+    let rendered = run_pool(&audible, options.jobs, &|unit|
+    {
+        let start = Instant::now();
+        let dst = build_dir.join(format!("{}.wav", unit.name));
+        let chunks = render_unit(backend, timing, unit, cache_dir, &dst, options.chunk_size)?;
+        align_track(&dst, &unit.notes, timing)?;
+
+        let split = if chunks > 1 { format!(", {} chunks", chunks) } else { String::new() };
+        println!("[{}/{}] {} ({:.1}s{})", done.fetch_add(1, Ordering::SeqCst) + 1, audible.len(),
+            unit.name, start.elapsed().as_secs_f64(), split);
+        Ok(dst)
+    })?;
+
+    // sections follow one another at their nominal lengths
+    let mut section_start = 0;
+    let mut section_starts = vec![];
+    let mut section_wavs = vec![];
+    for section in &sections
+    {
+        let start = section_start;
+        section_starts.push(start);
+        section_start += units.iter().filter(|unit| unit.section == section.id)
+            .map(|unit| unit.notes.iter().map(|n| n.dur_ms).sum::<i32>())
+            .max().unwrap_or(0);
+
+        let trackfiles : Vec<TrackMix> = audible.iter().zip(&rendered)
+            .filter(|(unit, _)| unit.section == section.id)
+            .map(|(unit, path)| (path.clone(), unit.envelope.clone(), unit.channel_gains))
+            .collect();
+        if trackfiles.is_empty()
+        {
+            continue;
+        }
+
         let section_out = build_dir.join(format!("section-{}-output.wav", section.id));
+        overlay_tracks(&trackfiles, &section_out)?;
+        section_wavs.push((start, section_out));
+    }
+
+    let frames = master_song(&section_wavs, section_start, &song_out, &options.master)?;
 
-        let trackfiles = section.tracks.iter().map(|(track_id, measures)|
+    if options.stems
+    {
+        let mut tracks : Vec<u32> = audible.iter().map(|unit| unit.track).collect();
+        tracks.sort();
+        tracks.dedup();
+        let stems : Vec<(String, Vec<Vec<TrackMix>>)> = tracks.iter().map(|track|
         {
-            let sec: String = measures.iter().map(|m| {
-                m.notes.iter().map(|n: &NoteDecl| {
-                    to_moonbase_str(&to_moonbase_note(section.tempo, n))
-                }).collect::<String>()
-            }).collect();
+            let name = comp.part_name(*track).cloned().unwrap_or(format!("track-{}", track));
+            let by_section = sections.iter().map(|section|
+            {
+                audible.iter().zip(&rendered)
+                    .filter(|(unit, _)| unit.section == section.id && unit.track == *track)
+                    .map(|(unit, path)| (path.clone(), unit.envelope.clone(), unit.channel_gains))
+                    .collect()
+            })
+            .collect();
+            (name, by_section)
+        })
+        .collect();
+        write_stems(&stems, &section_starts, frames, &build_dir.join("stems"))?;
+    }
 
-            let mb_txt_path = text_dir.join(
-                format!("section-{}-track-{}.txt", section.id, track_id));
+    Ok(())
+}
 
-            if mb_txt_path.exists()
+// writes each track across the whole song, silent wherever it doesn't
+// sing, so every stem starts with the song and is exactly as long. stems
+// are mixed like the song but left unmastered, in floating point; summed,
+// they give the song as it was before normalizing and limiting.
+fn write_stems(stems: &[(String, Vec<Vec<TrackMix>>)], section_starts: &[i32],
+    frames: usize, dir: &Path) -> CompileResult<()>
+{
+    create_dir(dir)?;
+    for (name, by_section) in stems
+    {
+        let mut spec = None;
+        let mut parts = vec![];
+        for (tracks, start) in by_section.iter().zip(section_starts)
+        {
+            if tracks.is_empty()
             {
-                std::fs::remove_file(&mb_txt_path)?;
+                continue;
             }
-            std::fs::write(mb_txt_path, &sec)?;
+            let (samples, s) = mix_tracks(tracks)?;
+            spec.get_or_insert(s);
+            parts.push((*start, samples));
+        }
 
-            let res = generate_moonbase_or_error(&sec, cache_dir)?;
+        let spec = match spec
+        {
+            Some(spec) => spec,
+            None => continue,
+        };
+        let mut stem = place_at_offsets(&parts, spec.channels, spec.sample_rate);
+        stem.resize(frames * spec.channels as usize, 0.0);
+        write_float_samples(&stem, &dir.join(format!("{}.wav", name)), &spec)?;
+    }
+    Ok(())
+}
 
-            let dst: std::path::PathBuf = build_dir.join(format!(
-                "section-{}-track-{}.wav", section.id, track_id
-            ));
-            std::fs::copy(&res, &dst);
+#[test]
+fn section_placement()
+{
+    // the first section ends on a rest, which the engine's tail used to
+    // be trimmed into
+    let comp = crate::semantics::semantics_of("60BPM [1] | ah ah - - |\n======\n[1] | oh:2 oh:2 |").unwrap();
 
-            return Ok::<PathBuf, CompileError>(res);
+    let build_dir = std::env::temp_dir().join("regolith-section-placement");
+    let _ = std::fs::remove_dir_all(&build_dir);
+    std::fs::create_dir_all(&build_dir).unwrap();
+    let options = RenderOptions { jobs: 1, ..RenderOptions::default() };
+    generate_mb_code(&comp, &crate::tts::StubBackend, &Timing::default(), &build_dir.join("cache"), &build_dir,
+        &options).unwrap();
 
-        })
-        .collect::<CompileResult<Vec<PathBuf>>>()?;
+    let (samples, spec) = load_samples(&[build_dir.join("song.wav")]).unwrap();
+    let rate = spec.sample_rate as usize;
+    assert_eq!(spec.channels, 2);
+    let left : Vec<i16> = samples[0].iter().step_by(2).cloned().collect();
+    let onsets = crate::align::find_onsets(&left, spec.sample_rate);
+    assert_eq!(onsets, vec![0, 4 * rate]);
+    assert_eq!(left.len(), 8 * rate);
+}
 
-        overlay_tracks(&trackfiles, &section_out)?;
+#[test]
+fn speaker_switching()
+{
+    let comp = crate::semantics::semantics_of(
+        "PART soprano = [1]\n[soprano] VOICE betty | ah ah ah ah |\n[2] | oh:4 |").unwrap();
 
-        Ok::<PathBuf, CompileError>(section_out)
-    })
-    .collect::<Result<Vec<_>, _>>()?;
+    let build_dir = std::env::temp_dir().join("regolith-speaker-switching");
+    let _ = std::fs::remove_dir_all(&build_dir);
+    std::fs::create_dir_all(&build_dir).unwrap();
 
-    append_tracks(&section_wavs, &song_out)?;
+    // the smallest chunks still each start in the right voice
+    let options = RenderOptions { jobs: 1, chunk_size: 1, ..RenderOptions::default() };
+    generate_mb_code(&comp, &crate::tts::StubBackend, &Timing::default(), &build_dir.join("cache"), &build_dir,
+        &options).unwrap();
 
-    Ok(())
+    let text = |name: &str| std::fs::read_to_string(build_dir.join("mb_text").join(name)).unwrap();
+    assert!(text("section-0-part-soprano.txt").starts_with("[:nb]["));
+    assert!(text("section-0-track-2.txt").starts_with("[oh<"));
+}
+
+#[test]
+fn rehearsal_renders()
+{
+    let comp = crate::semantics::semantics_of(
+        "60BPM [1 pan=-1] | ah ah ah ah |
+[2 pan=1] | - - oh:2 |
+[3] | - - - - |").unwrap();
+
+    let root = std::env::temp_dir().join("regolith-rehearsal-renders");
+    let _ = std::fs::remove_dir_all(&root);
+    let render = |solo: Vec<u32>, mute: Vec<u32>|
+    {
+        let build_dir = root.join(format!("solo-{:?}-mute-{:?}", solo, mute));
+        std::fs::create_dir_all(&build_dir).unwrap();
+        let options = RenderOptions { jobs: 1, solo, mute, ..RenderOptions::default() };
+        generate_mb_code(&comp, &crate::tts::StubBackend, &Timing::default(), &root.join("cache"), &build_dir,
+            &options).map(|_| build_dir)
+    };
+
+    // soloing the late entry renders only it, still in its place and on
+    // its side, in a song as long as the full one
+    let build_dir = render(vec![2], vec![]).unwrap();
+    assert!(!build_dir.join("section-0-track-1.wav").exists());
+    let (samples, spec) = load_samples(&[build_dir.join("song.wav")]).unwrap();
+    let left : Vec<i16> = samples[0].iter().step_by(2).cloned().collect();
+    let right : Vec<i16> = samples[0].iter().skip(1).step_by(2).cloned().collect();
+    assert_eq!(left.len(), 4 * spec.sample_rate as usize);
+    assert!(left.iter().all(|s| s.abs() <= 1));
+    assert_eq!(crate::align::find_onsets(&right, spec.sample_rate), vec![2 * spec.sample_rate as usize]);
+
+    let build_dir = render(vec![], vec![2]).unwrap();
+    assert!(build_dir.join("section-0-track-1.wav").exists());
+    assert!(!build_dir.join("section-0-track-2.wav").exists());
+
+    assert!(matches!(render(vec![1, 2], vec![1, 2]), Err(CompileError::Generic(_))));
+}
+
+#[test]
+fn parallel_rendering()
+{
+    let comp = crate::semantics::semantics_of(
+        "PART alto = [3]\n[1] | 1 . . . . | 3 . . . . |\n[2] | 5 . . . . | {1 3 5} . . . . |\n[alto] | - - - - | ah:4 |\n\
+        ======\n[alto] | oh:4 |").unwrap();
+
+    let root = std::env::temp_dir().join("regolith-parallel-rendering");
+    let _ = std::fs::remove_dir_all(&root);
+    let render = |jobs: usize|
+    {
+        let build_dir = root.join(format!("jobs-{}", jobs));
+        std::fs::create_dir_all(&build_dir).unwrap();
+        let options = RenderOptions { jobs, ..RenderOptions::default() };
+        generate_mb_code(&comp, &crate::tts::StubBackend, &Timing::default(), &root.join("cache"), &build_dir,
+            &options).unwrap();
+        assert!(build_dir.join("section-0-track-2-voice-2.wav").exists());
+        assert!(build_dir.join("section-1-part-alto.wav").exists());
+        std::fs::read(build_dir.join("song.wav")).unwrap()
+    };
+
+    assert_eq!(render(1), render(4));
+}
+
+#[test]
+fn stem_export()
+{
+    // the second part only comes in for the second section, and shares
+    // its name with the section mixes
+    let comp = crate::semantics::semantics_of(
+        "60BPM PART output = [2]\n[1 pan=-0.5] | ah ah ah ah |\n======\n[1] | oh:4 |\n[output pan=0.5] | - - ah:2 |").unwrap();
+
+    let build_dir = std::env::temp_dir().join("regolith-stem-export");
+    let _ = std::fs::remove_dir_all(&build_dir);
+    std::fs::create_dir_all(&build_dir).unwrap();
+    let master = MasterOptions { normalize: crate::mix::Normalize::Off, ceiling_db: 0.0 };
+    let options = RenderOptions { jobs: 1, master, stems: true, ..RenderOptions::default() };
+    generate_mb_code(&comp, &crate::tts::StubBackend, &Timing::default(), &build_dir.join("cache"), &build_dir,
+        &options).unwrap();
+
+    let (song, spec) = load_float_samples(&[build_dir.join("song.wav")]).unwrap();
+    let (stems, stem_spec) = load_float_samples(&[build_dir.join("stems").join("track-1.wav"),
+        build_dir.join("stems").join("output.wav")]).unwrap();
+    assert_eq!((stem_spec.channels, stem_spec.sample_rate), (spec.channels, spec.sample_rate));
+    assert!(stems.iter().all(|s| s.len() == song[0].len()));
+
+    // silent until the part's entry, two beats into the second section
+    let entry = 6 * spec.sample_rate as usize * 2;
+    assert!(stems[1][..entry].iter().all(|s| *s == 0.0));
+    assert!(stems[1][entry..].iter().any(|s| s.abs() > 0.01));
+
+    // and together they make up the unmastered song
+    assert!(song[0].iter().enumerate().all(|(i, s)| (stems[0][i] + stems[1][i] - s).abs() < 1e-3));
 }
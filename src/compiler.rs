@@ -3,8 +3,11 @@ use crate::lexer::lex_markdown;
 use crate::lexer::lex_multiline_string;
 use crate::parser::parse_to_ast;
 use crate::semantics::{Composition, do_semantics};
-use crate::codegen::generate_mb_code;
+use crate::codegen::{generate_mb_code, RenderOptions};
+use crate::calibrate::load_timing;
+use crate::midi::write_midi;
 use crate::moonbase::create_dir;
+use crate::tts::{HttpBackend, TtsBackend};
 
 use std::path::Path;
 
@@ -14,6 +17,33 @@ pub enum CompileInput<'a>
     Markdown(&'a Path)
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Emit
+{
+    Wav,
+    Midi
+}
+
+pub struct CompileOptions
+{
+    pub backend: Box<dyn TtsBackend>,
+    pub emit: Emit,
+    pub render: RenderOptions
+}
+
+impl Default for CompileOptions
+{
+    fn default() -> Self
+    {
+        CompileOptions
+        {
+            backend: Box::new(HttpBackend::default()),
+            emit: Emit::Wav,
+            render: RenderOptions::default()
+        }
+    }
+}
+
 fn print_composition(comp: &Composition)
 {
     // for section in &comp.sections
@@ -25,7 +55,7 @@ fn print_composition(comp: &Composition)
     // }
 }
 
-pub fn compile(input: &CompileInput, build_root: &Path) -> CompileResult<()>
+pub fn compile(input: &CompileInput, build_root: &Path, options: &CompileOptions) -> CompileResult<()>
 {
     create_dir(&build_root)?;
 
@@ -76,7 +106,19 @@ pub fn compile(input: &CompileInput, build_root: &Path) -> CompileResult<()>
     let tree = parse_to_ast(&tokens)?;
     let comp = do_semantics(&tree)?;
     print_composition(&comp);
-    generate_mb_code(&comp, &cache_dir, &build_dir)?;
+    match options.emit
+    {
+        Emit::Wav =>
+        {
+            println!("Rendering with {} backend", options.backend.name());
+            let timing = load_timing(&cache_dir, options.backend.as_ref())?;
+            generate_mb_code(&comp, options.backend.as_ref(), &timing, &cache_dir, &build_dir, &options.render)?;
+        },
+        Emit::Midi =>
+        {
+            write_midi(&comp, &build_dir.join("song.mid"))?;
+        }
+    }
 
     println!("Done.\n");
 
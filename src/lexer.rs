@@ -3,81 +3,142 @@ use fraction::Fraction;
 use regex_macro::regex;
 use crate::types::*;
 use std::path::Path;
+use std::sync::Arc;
 
 use crate::moonbase::MoonbaseNote;
 
-static PITCH_MAP : [(&str, u8); 49] =
+static LETTER_SEMITONES : [(char, i32); 7] =
 [
-    // ambiguous map -- get rid of it!
-    ("A"  , 10),
-    ("A#" , 11),
-    ("B"  , 12),
-    ("C"  , 13),
-    ("C#" , 14),
-    ("D"  , 15),
-    ("D#" , 16),
-    ("E"  , 17),
-    ("F"  , 18),
-    ("F#" , 19),
-    ("G"  , 20),
-    ("G#" , 21),
-
-    ("C1" , 1),
-    ("C1#", 2),
-    ("D1" , 3),
-    ("D1#", 4),
-    ("E1" , 5),
-    ("F1" , 6),
-    ("F1#", 7),
-    ("G1" , 8),
-    ("G1#", 9),
-    ("A1" , 10),
-    ("A1#", 11),
-    ("B1" , 12),
-    ("C2" , 13),
-    ("C2#", 14),
-    ("D2" , 15),
-    ("D2#", 16),
-    ("E2" , 17),
-    ("F2" , 18),
-    ("F2#", 19),
-    ("G2" , 20),
-    ("G2#", 21),
-    ("A2" , 22),
-    ("A2#", 23),
-    ("B2" , 24),
-    ("C3" , 25),
-    ("C3#", 26),
-    ("D3" , 27),
-    ("D3#", 28),
-    ("E3" , 29),
-    ("F3" , 30),
-    ("F3#", 31),
-    ("G3" , 32),
-    ("G3#", 33),
-    ("A3" , 34),
-    ("A3#", 35),
-    ("B3" , 36),
-    ("C4" , 37)
+    ('C', 0),
+    ('D', 2),
+    ('E', 4),
+    ('F', 5),
+    ('G', 7),
+    ('A', 9),
+    ('B', 11),
 ];
 
+// the engine's tone 1 is C2 in scientific pitch notation
+const TONE_ONE_OCTAVE: i32 = 2;
+
+// octave-less names pick the twelve tones around the middle of the
+// engine's range, A2 up to G#3
+const BARE_PITCH_LOW: i32 = 10;
+const BARE_PITCH_HIGH: i32 = 21;
+
+// Reads a pitch name like "C", "Bb3", "F#4" or "Ebb2" into a tone number,
+// without checking that the engine can actually sing it. Accidentals come
+// before the octave, and octave numbers follow scientific pitch notation,
+// so the octave changes at C: "Cb4" is the tone just below "C4".
+pub fn pitch_string_to_tone(pitch: &str) -> Option<i32>
+{
+    let cap = regex!(r"^([A-G])(##|#|bb|b)?(\d+)?$").captures(pitch)?;
+    let letter = cap[1].chars().next()?;
+    let (_, mut semitones) = LETTER_SEMITONES.iter().find(|(c, _)| *c == letter)?;
+    semitones += match cap.get(2).map(|m| m.as_str())
+    {
+        Some("##") => 2,
+        Some("#")  => 1,
+        Some("b")  => -1,
+        Some("bb") => -2,
+        _ => 0,
+    };
+
+    match cap.get(3)
+    {
+        Some(octave) =>
+        {
+            let octave : i32 = octave.as_str().parse().ok()?;
+            (octave - TONE_ONE_OCTAVE).checked_mul(12)?.checked_add(semitones + 1)
+        },
+        None =>
+        {
+            let mut tone = BARE_PITCH_LOW + 3 + semitones;
+            while tone > BARE_PITCH_HIGH
+            {
+                tone -= 12;
+            }
+            while tone < BARE_PITCH_LOW
+            {
+                tone += 12;
+            }
+            Some(tone)
+        }
+    }
+}
+
 pub fn pitch_string_to_id(pitch: &str) -> Option<ToneId>
 {
-    let (_, i) = PITCH_MAP.iter().find(|(s, _)| *s == pitch)?;
-    Some(ToneId(*i))
+    let tone = pitch_string_to_tone(pitch)?;
+    if tone < MIN_TONE as i32 || tone > MAX_TONE as i32
+    {
+        return None;
+    }
+    Some(ToneId(tone as u8))
+}
+
+// canonical name for a tone: sharps, with an octave number
+pub fn tone_id_to_pitch_string(tone: ToneId) -> Option<String>
+{
+    let ToneId(t) = tone;
+    if t < MIN_TONE || t > MAX_TONE
+    {
+        return None;
+    }
+    let names = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+    let index = t as usize - 1;
+    Some(format!("{}{}", names[index % 12], index / 12 + TONE_ONE_OCTAVE as usize))
 }
 
 #[test]
 fn pitch_string_conversions()
 {
-    assert_eq!(pitch_string_to_id("C1"),  Some(ToneId(1)));
-    assert_eq!(pitch_string_to_id("D2#"), Some(ToneId(16)));
-    assert_eq!(pitch_string_to_id("A2#"), Some(ToneId(23)));
-    assert_eq!(pitch_string_to_id("G3"),  Some(ToneId(32)));
-    assert_eq!(pitch_string_to_id("C4"),  Some(ToneId(37)));
-    assert_eq!(pitch_string_to_id(""),    None);
-    assert_eq!(pitch_string_to_id("J3"),  None);
-    assert_eq!(pitch_string_to_id("Bb"),  None);
+    assert_eq!(pitch_string_to_id("C2"),   Some(ToneId(1)));
+    assert_eq!(pitch_string_to_id("D#3"),  Some(ToneId(16)));
+    assert_eq!(pitch_string_to_id("Eb3"),  Some(ToneId(16)));
+    assert_eq!(pitch_string_to_id("A#3"),  Some(ToneId(23)));
+    assert_eq!(pitch_string_to_id("Bb3"),  Some(ToneId(23)));
+    assert_eq!(pitch_string_to_id("G4"),   Some(ToneId(32)));
+    assert_eq!(pitch_string_to_id("C5"),   Some(ToneId(37)));
+    assert_eq!(pitch_string_to_id("Cb4"),  Some(ToneId(24)));
+    assert_eq!(pitch_string_to_id("B#3"),  Some(ToneId(25)));
+    assert_eq!(pitch_string_to_id("F##3"), Some(ToneId(20)));
+    assert_eq!(pitch_string_to_id("Abb3"), Some(ToneId(20)));
+    assert_eq!(pitch_string_to_id(""),     None);
+    assert_eq!(pitch_string_to_id("J3"),   None);
+    assert_eq!(pitch_string_to_id("C3#"),  None);
+    assert_eq!(pitch_string_to_id("C#b3"), None);
+
+    // out of the engine's range
+    assert_eq!(pitch_string_to_tone("B1"), Some(0));
+    assert_eq!(pitch_string_to_id("B1"),   None);
+    assert_eq!(pitch_string_to_id("Cb2"),  None);
+    assert_eq!(pitch_string_to_id("C#5"),  None);
+    assert_eq!(pitch_string_to_id("C9"),   None);
+    assert_eq!(pitch_string_to_id("C200000000"), None);
+    assert_eq!(pitch_string_to_id("C99999999999"), None);
+
+    // bare names stay within A2..G#3
+    assert_eq!(pitch_string_to_id("A"),  Some(ToneId(10)));
+    assert_eq!(pitch_string_to_id("Bb"), Some(ToneId(11)));
+    assert_eq!(pitch_string_to_id("Cb"), Some(ToneId(12)));
+    assert_eq!(pitch_string_to_id("C"),  Some(ToneId(13)));
+    assert_eq!(pitch_string_to_id("G#"), Some(ToneId(21)));
+    assert_eq!(pitch_string_to_id("Ab"), Some(ToneId(21)));
+    assert_eq!(pitch_string_to_id("B#"), Some(ToneId(13)));
+
+    assert_eq!(tone_id_to_pitch_string(ToneId(1)),  Some("C2".to_string()));
+    assert_eq!(tone_id_to_pitch_string(ToneId(13)), Some("C3".to_string()));
+    assert_eq!(tone_id_to_pitch_string(ToneId(23)), Some("A#3".to_string()));
+    assert_eq!(tone_id_to_pitch_string(ToneId(37)), Some("C5".to_string()));
+    assert_eq!(tone_id_to_pitch_string(ToneId(0)),  None);
+    assert_eq!(tone_id_to_pitch_string(ToneId(38)), None);
+
+    for t in MIN_TONE..=MAX_TONE
+    {
+        let name = tone_id_to_pitch_string(ToneId(t)).unwrap();
+        assert_eq!(pitch_string_to_id(&name), Some(ToneId(t)));
+    }
 }
 
 static NAMED_SCALE_MAP : [(&str, &[u8; 12]); 4] =
@@ -111,7 +172,10 @@ pub fn read_literals_from_multiline_string(source: &str, filename: &str) -> Comp
     let mut result = Vec::new();
     let mut idno = 0;
 
-    let reg = regex!(r"[^\s]+");
+    // chord braces are literals of their own, even when written
+    // up against the pitches they enclose; part and voice directives
+    // and track headers with attributes are one literal, spaces and all
+    let reg = regex!(r"PART\s+\w+\s*=\s*\[\d+\]|VOICE\s+\w+|\[\w+\s[^\]]*\]|[{}]|[^\s{}]+");
 
     for (lineno, line) in source.lines().enumerate()
     {
@@ -120,15 +184,18 @@ pub fn read_literals_from_multiline_string(source: &str, filename: &str) -> Comp
             continue;
         }
 
+        let text: Arc<str> = Arc::from(line);
+
         for m in reg.find_iter(&line)
         {
             let l = Literal
             {
-                colno: m.start(),
+                colno: m.start() + 1,
                 filename: filename.to_string(),
-                lineno,
+                lineno: lineno + 1,
                 literal: m.as_str().to_string(),
-                idno
+                idno,
+                source: text.clone()
             };
             idno += 1;
             result.push(l);
@@ -136,11 +203,12 @@ pub fn read_literals_from_multiline_string(source: &str, filename: &str) -> Comp
 
         result.push(Literal
         {
-            colno: line.len(),
+            colno: line.len() + 1,
             filename: filename.to_string(),
-            lineno,
+            lineno: lineno + 1,
             literal: "<eol>".to_string(),
-            idno
+            idno,
+            source: text
         });
         idno += 1;
     }
@@ -160,7 +228,10 @@ pub fn read_literals_from_markdown(filename: &Path) -> CompileResult<Vec<Literal
     let mut result = Vec::new();
     let mut idno = 0;
 
-    let reg = regex!(r"[^\s]+");
+    // chord braces are literals of their own, even when written
+    // up against the pitches they enclose; part and voice directives
+    // and track headers with attributes are one literal, spaces and all
+    let reg = regex!(r"PART\s+\w+\s*=\s*\[\d+\]|VOICE\s+\w+|\[\w+\s[^\]]*\]|[{}]|[^\s{}]+");
 
     let mut codeblock = false;
 
@@ -191,6 +262,8 @@ pub fn read_literals_from_markdown(filename: &Path) -> CompileResult<Vec<Literal
             continue;
         }
 
+        let text: Arc<str> = Arc::from(line);
+
         for m in reg.find_iter(&line)
         {
             let l = Literal
@@ -199,7 +272,8 @@ pub fn read_literals_from_markdown(filename: &Path) -> CompileResult<Vec<Literal
                 filename: name.clone(),
                 lineno: lineno + 1,
                 literal: m.as_str().to_string(),
-                idno
+                idno,
+                source: text.clone()
             };
             idno += 1;
             result.push(l);
@@ -211,7 +285,8 @@ pub fn read_literals_from_markdown(filename: &Path) -> CompileResult<Vec<Literal
             filename: name.clone(),
             lineno: lineno + 1,
             literal: "<eol>".to_string(),
-            idno
+            idno,
+            source: text
         });
         idno += 1;
     }
@@ -279,6 +354,23 @@ fn parse_dynamic_level(level: &str) -> Option<DynamicLevel>
     }
 }
 
+pub fn parse_voice(name: &str) -> Option<Voice>
+{
+    match name
+    {
+        "paul"   => Some(Voice::Paul),
+        "harry"  => Some(Voice::Harry),
+        "frank"  => Some(Voice::Frank),
+        "dennis" => Some(Voice::Dennis),
+        "betty"  => Some(Voice::Betty),
+        "ursula" => Some(Voice::Ursula),
+        "wendy"  => Some(Voice::Wendy),
+        "rita"   => Some(Voice::Rita),
+        "kit"    => Some(Voice::Kit),
+        _        => None
+    }
+}
+
 #[test]
 fn dynamic_lexing()
 {
@@ -288,6 +380,60 @@ fn dynamic_lexing()
     lex_assert!("MEZZOFORTE", Token::Dynamic(DynamicLevel::Mezzoforte));
     lex_assert!("FORTE",      Token::Dynamic(DynamicLevel::Forte));
     lex_assert!("FORTISSIMO", Token::Dynamic(DynamicLevel::Fortissimo));
+    lex_assert!("CRESC",      Token::Hairpin(Hairpin::Crescendo));
+    lex_assert!("DIM",        Token::Hairpin(Hairpin::Decrescendo));
+}
+
+#[test]
+fn time_signature_lexing()
+{
+    lex_assert!("4/4",   Token::TimeSignature((4, 4)));
+    lex_assert!("6/8",   Token::TimeSignature((6, 8)));
+    lex_assert!("3/2",   Token::TimeSignature((3, 2)));
+    lex_assert!("12/16", Token::TimeSignature((12, 16)));
+    lex_assert!("5/1",   Token::TimeSignature((5, 1)));
+
+    lex_nope!("4/0");
+    lex_nope!("4/3");
+    lex_nope!("7/12");
+    lex_nope!("0/4");
+    lex_nope!("4/256");
+}
+
+// the attributes in a track header after its number, e.g.
+// gain=-3dB pan=0.3; each may be given at most once
+fn parse_track_attributes(s: &str) -> Option<TrackAttributes>
+{
+    let attribute_re = regex!(r"^\s*(\w+)=(\S+)");
+    let gain_re = regex!(r"^([+-]?\d+(?:\.\d+)?)(?:dB)?$");
+
+    let mut attributes = TrackAttributes::default();
+    let mut rest = s.trim_end();
+    while !rest.is_empty()
+    {
+        let cap = attribute_re.captures(rest)?;
+        rest = &rest[cap[0].len()..];
+
+        let unset = match &cap[1]
+        {
+            "gain" =>
+            {
+                let db : f32 = gain_re.captures(&cap[2])?[1].parse().ok()?;
+                attributes.gain_db.replace(db).is_none()
+            },
+            "pan" =>
+            {
+                let pan : f32 = cap[2].parse().ok()?;
+                (-1.0..=1.0).contains(&pan) && attributes.pan.replace(pan).is_none()
+            },
+            _ => false,
+        };
+        if !unset
+        {
+            return None;
+        }
+    }
+    Some(attributes)
 }
 
 fn get_nth_capture(captures: &[Option<String>], i: usize) -> Option<String>
@@ -302,13 +448,46 @@ fn lex_literal(literal: &str) -> Option<Token>
         return Some(Token::Endline());
     }
 
+    if literal == "{"
+    {
+        return Some(Token::ChordOpen);
+    }
+
+    if literal == "}"
+    {
+        return Some(Token::ChordClose);
+    }
+
+    if literal == "RIT"
+    {
+        return Some(Token::TempoRamp(TempoRamp::Ritardando));
+    }
+
+    if literal == "ACCEL"
+    {
+        return Some(Token::TempoRamp(TempoRamp::Accelerando));
+    }
+
+    if literal == "CRESC"
+    {
+        return Some(Token::Hairpin(Hairpin::Crescendo));
+    }
+
+    if literal == "DIM"
+    {
+        return Some(Token::Hairpin(Hairpin::Decrescendo));
+    }
+
     let measure_bar_re = regex!(r"^(:?)\|(:?)$");
+    let end_repeat_re = regex!(r"^:\|x(\d+)$");
     let bpm_token_re = regex!(r"^(\d+)BPM$");
-    let track_token_re = regex!(r"^\[(\d+)\]$");
-    let pitch_token_re = regex!(r"^[A-Z]\d?#?$");
-    let scale_degree_re = regex!(r"^(\d+)([#b])?$");
-    let note_token_re = regex!(r"^([a-z\.]+)\-?([a-z\.]+)?(:(\d+))?(\/(\d+))?$");
-    let scale_decl_re = regex!(r"^([A-G]\d*[#b]?)(\[(\d+)\]|PENTA|MAJOR|MINOR|CHROM)?$");
+    let track_token_re = regex!(r"^\[(\d+|[A-Za-z]\w*)(\s[^\]]*)?\]$");
+    let part_decl_re = regex!(r"^PART\s+([A-Za-z]\w*)\s*=\s*\[(\d+)\]$");
+    let voice_decl_re = regex!(r"^VOICE\s+(\w+)$");
+    let pitch_token_re = regex!(r"^[A-G](##|#|bb|b)?\d*$");
+    let scale_degree_re = regex!(r"^([1-9]\d*)([#b])?$");
+    let note_token_re = regex!(r"^([a-z\.]+)\-?([a-z\.]+)?(:(\d+))?(\/(\d+))?(~)?$");
+    let scale_decl_re = regex!(r"^([A-G](?:##|#|bb|b)?\d*)(\[(\d+)\]|PENTA|MAJOR|MINOR|CHROM)?$");
     let dynamic_decl_re = regex!(r"^FORTISSIMO|FORTE|MEZZOFORTE|MEZZOPIANO|PIANO|PIANISSIMO$");
     let rest_decl_re = regex!(r"^-(:(\d+))?(\/(\d+))?$");
     let section_marker_re = regex!(r"^===([^\s-]*)===$");
@@ -322,8 +501,29 @@ fn lex_literal(literal: &str) -> Option<Token>
 
     lex_rule!(&literal, track_token_re, |cap: &[Option<String>]|
     {
-        let track_id : u32 = get_nth_capture(cap, 1)?.parse().ok()?;
-        Some(Token::Track(track_id))
+        let name = get_nth_capture(cap, 1)?;
+        let track = match name.parse::<u32>()
+        {
+            Ok(track_id) => TrackRef::Id(track_id),
+            Err(_) => TrackRef::Part(name),
+        };
+        let attributes = match get_nth_capture(cap, 2)
+        {
+            Some(s) => parse_track_attributes(&s)?,
+            None => TrackAttributes::default(),
+        };
+        Some(Token::Track(track, attributes))
+    });
+
+    lex_rule!(&literal, part_decl_re, |cap: &[Option<String>]|
+    {
+        let track_id : u32 = get_nth_capture(cap, 2)?.parse().ok()?;
+        Some(Token::Part(get_nth_capture(cap, 1)?, track_id))
+    });
+
+    lex_rule!(&literal, voice_decl_re, |cap: &[Option<String>]|
+    {
+        Some(Token::Voice(parse_voice(&get_nth_capture(cap, 1)?)?))
     });
 
     lex_rule!(&literal, pitch_token_re, |cap: &[Option<String>]|
@@ -351,7 +551,8 @@ fn lex_literal(literal: &str) -> Option<Token>
         {
             prefix: cap[1].as_ref().unwrap_or(&"".to_string()).clone(),
             suffix: cap[2].as_ref().unwrap_or(&"".to_string()).clone(),
-            beats: Fraction::new(numer, denom)
+            beats: Fraction::new(numer, denom),
+            tie: cap[7].is_some()
         };
         Some(Token::Note(n))
     });
@@ -388,7 +589,13 @@ fn lex_literal(literal: &str) -> Option<Token>
     lex_rule!(&literal, scale_degree_re, |cap: &[Option<String>]|
     {
         let d : u8 = get_nth_capture(cap, 1)?.parse().ok()?;
-        Some(Token::ScaleDegree(d))
+        let accidental = match cap[2].as_deref()
+        {
+            Some("#") => Accidental::Sharp,
+            Some("b") => Accidental::Flat,
+            _ => Accidental::Natural,
+        };
+        Some(Token::ScaleDegree(d, accidental))
     });
 
     lex_rule!(&literal, measure_bar_re, |cap: &[Option<String>]|
@@ -398,6 +605,12 @@ fn lex_literal(literal: &str) -> Option<Token>
         Some(Token::MeasureBar(prefix == ":", suffix == ":"))
     });
 
+    lex_rule!(&literal, end_repeat_re, |cap: &[Option<String>]|
+    {
+        let count : u8 = get_nth_capture(cap, 1)?.parse().ok()?;
+        Some(Token::EndRepeat(count))
+    });
+
     lex_rule!(&literal, rest_decl_re, |cap: &[Option<String>]|
     {
         let numer : u64 = match cap[2].as_ref()
@@ -416,7 +629,8 @@ fn lex_literal(literal: &str) -> Option<Token>
         {
             prefix: "_".to_string(),
             suffix: "".to_string(),
-            beats: Fraction::new(numer, denom)
+            beats: Fraction::new(numer, denom),
+            tie: false
         };
 
         Some(Token::Note(n))
@@ -432,22 +646,49 @@ fn lex_literal(literal: &str) -> Option<Token>
     {
         let numer : u8 = get_nth_capture(cap, 1)?.parse().ok()?;
         let denom : u8 = get_nth_capture(cap, 2)?.parse().ok()?;
+        // the denominator is a note value: whole, half, quarter, ...
+        if numer == 0 || !denom.is_power_of_two()
+        {
+            return None;
+        }
         Some(Token::TimeSignature((numer, denom)))
     });
 
     None
 }
 
+// a well-formed pitch (or scale root) that the engine can't sing gets a
+// clearer error than plain invalid syntax
+fn lexing_error(lit: &Literal) -> CompileError
+{
+    let scale_re = regex!(r"^(.+?)(\[\d+\]|PENTA|MAJOR|MINOR|CHROM)?$");
+    let pitch = scale_re.captures(&lit.literal)
+        .and_then(|cap| pitch_string_to_tone(&cap[1]));
+    match pitch
+    {
+        Some(tone) if tone < MIN_TONE as i32 || tone > MAX_TONE as i32 =>
+            CompileError::PitchOutOfRange(lit.clone()),
+        _ if regex!(r"^\[\w+\s").is_match(&lit.literal) =>
+            CompileError::InvalidTrackHeader(lit.clone()),
+        _ if lit.literal.starts_with("VOICE") =>
+            CompileError::UnknownVoice(lit.clone()),
+        _ => CompileError::InvalidSyntax(lit.clone()),
+    }
+}
+
 pub fn lex_literals(literals: &Vec<Literal>) -> CompileResult<Vec<(Literal, Token)>>
 {
     let mut ret = vec![];
+    let mut errors = vec![];
     for lit in literals
     {
-        let token = lex_literal(&lit.literal)
-            .ok_or(CompileError::InvalidSyntax(lit.clone()))?;
-        ret.push((lit.clone(), token));
+        match lex_literal(&lit.literal)
+        {
+            Some(token) => ret.push((lit.clone(), token)),
+            None => errors.push(lexing_error(lit)),
+        }
     }
-    Ok(ret)
+    collect_diagnostics(ret, errors)
 }
 
 pub fn lex_multiline_string(source: &str) -> CompileResult<Vec<(Literal, Token)>>
@@ -479,7 +720,8 @@ fn note_lexing()
     {
         prefix: "ih".to_string(),
         suffix: "s".to_string(),
-        beats: Fraction::new(3u64, 2u64)
+        beats: Fraction::new(3u64, 2u64),
+        tie: false
     }));
 
     lex_assert!("uh-n/2",
@@ -487,7 +729,8 @@ fn note_lexing()
     {
         prefix: "uh".to_string(),
         suffix: "n".to_string(),
-        beats: Fraction::new(1u64, 2u64)
+        beats: Fraction::new(1u64, 2u64),
+        tie: false
     }));
 
     lex_assert!("ne/3",
@@ -495,15 +738,38 @@ fn note_lexing()
     {
         prefix: "ne".to_string(),
         suffix: "".to_string(),
-        beats: Fraction::new(1u64, 3u64)
+        beats: Fraction::new(1u64, 3u64),
+        tie: false
+    }));
+
+    lex_assert!("ah~",
+    Token::Note(RegoNote
+    {
+        prefix: "ah".to_string(),
+        suffix: "".to_string(),
+        beats: Fraction::new(1u64, 1u64),
+        tie: true
     }));
 
+    lex_assert!("ah-n:3/2~",
+    Token::Note(RegoNote
+    {
+        prefix: "ah".to_string(),
+        suffix: "n".to_string(),
+        beats: Fraction::new(3u64, 2u64),
+        tie: true
+    }));
+
+    lex_nope!("ah~~");
+    lex_nope!("-~");
+
     lex_assert!("-:12",
     Token::Note(RegoNote
     {
         prefix: "_".to_string(),
         suffix: "".to_string(),
-        beats: Fraction::new(12u64, 1u64)
+        beats: Fraction::new(12u64, 1u64),
+        tie: false
     }));
 }
 
@@ -513,18 +779,50 @@ fn absolute_pitch_lexing()
     lex_assert!("C", Token::AbsolutePitch(ToneId(13)));
     lex_assert!("D", Token::AbsolutePitch(ToneId(15)));
     lex_assert!("E", Token::AbsolutePitch(ToneId(17)));
+    lex_assert!("Bb", Token::AbsolutePitch(ToneId(11)));
+    lex_assert!("F#4", Token::AbsolutePitch(ToneId(31)));
+    lex_assert!("Ebb3", Token::AbsolutePitch(ToneId(15)));
+
+    lex_nope!("H");
+    lex_nope!("C2#");
+    lex_nope!("C6");
+}
+
+#[test]
+fn pitch_range_errors()
+{
+    match lex_multiline_string("C2 B1 CMAJOR C6MAJOR C#5 H2")
+    {
+        Err(CompileError::Diagnostics(errors)) =>
+        {
+            assert_eq!(errors.len(), 4);
+            assert!(matches!(&errors[0], CompileError::PitchOutOfRange(l) if l.literal == "B1"));
+            assert!(matches!(&errors[1], CompileError::PitchOutOfRange(l) if l.literal == "C6MAJOR"));
+            assert!(matches!(&errors[2], CompileError::PitchOutOfRange(l) if l.literal == "C#5"));
+            assert!(matches!(&errors[3], CompileError::InvalidSyntax(l) if l.literal == "H2"));
+        },
+        other => panic!("expected four diagnostics, got {:?}", other),
+    }
 }
 
 #[test]
 fn relative_pitch_lexing()
 {
-    lex_assert!("1", Token::ScaleDegree(1));
-    lex_assert!("2", Token::ScaleDegree(2));
-    lex_assert!("5", Token::ScaleDegree(5));
-    lex_assert!("13", Token::ScaleDegree(13));
+    lex_assert!("1", Token::ScaleDegree(1, Accidental::Natural));
+    lex_assert!("2", Token::ScaleDegree(2, Accidental::Natural));
+    lex_assert!("5", Token::ScaleDegree(5, Accidental::Natural));
+    lex_assert!("13", Token::ScaleDegree(13, Accidental::Natural));
+    lex_assert!("4#", Token::ScaleDegree(4, Accidental::Sharp));
+    lex_assert!("7b", Token::ScaleDegree(7, Accidental::Flat));
+    lex_assert!("10b", Token::ScaleDegree(10, Accidental::Flat));
+
+    lex_nope!("4##");
+    lex_nope!("b7");
 
     lex_nope!("-4");
     lex_nope!("352d");
+    lex_nope!("0");
+    lex_nope!("0#");
 }
 
 #[test]
@@ -551,15 +849,22 @@ fn scale_lexing()
         steps: vec![2, 2, 3, 2, 3]
     }));
 
-    lex_assert!("D3#CHROM", Token::Scale(Scale
+    lex_assert!("D#4CHROM", Token::Scale(Scale
     {
-        name: "D3#CHROM".to_string(),
+        name: "D#4CHROM".to_string(),
         tone_id: ToneId(28),
         steps: vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]
     }));
 
-    // no brackets
-    lex_nope!("Fb3");
+    lex_assert!("EbMAJOR", Token::Scale(Scale
+    {
+        name: "EbMAJOR".to_string(),
+        tone_id: ToneId(16),
+        steps: vec![2, 2, 1, 2, 2, 2, 1]
+    }));
+
+    // no scale, so this is just a pitch
+    lex_assert!("Fb3", Token::AbsolutePitch(ToneId(17)));
 
     // bad pitch
     lex_nope!("K4[22211]");
@@ -572,10 +877,10 @@ fn bar_lexing()
     lex_assert!("|:", Token::MeasureBar(false, true));
     lex_assert!(":|", Token::MeasureBar(true, false));
     lex_assert!(":|:", Token::MeasureBar(true, true));
-    // lex_assert!(":|x2",  Token::EndRepeat(2));
-    // lex_assert!(":|x6",  Token::EndRepeat(6));
-    // lex_assert!(":|x12", Token::EndRepeat(12));
-    // lex_assert!(":|x0",  Token::EndRepeat(0));
+    lex_assert!(":|x2",  Token::EndRepeat(2));
+    lex_assert!(":|x6",  Token::EndRepeat(6));
+    lex_assert!(":|x12", Token::EndRepeat(12));
+    lex_assert!(":|x0",  Token::EndRepeat(0));
 
     lex_nope!(":|x-1");
     lex_nope!(":|x-5");
@@ -590,6 +895,9 @@ fn bpm_lexing()
     lex_assert!("1103BPM", Token::Tempo(1103));
     lex_assert!("0BPM",    Token::Tempo(0));
 
+    lex_assert!("RIT",     Token::TempoRamp(TempoRamp::Ritardando));
+    lex_assert!("ACCEL",   Token::TempoRamp(TempoRamp::Accelerando));
+
     lex_nope!("-12BPM");
     lex_nope!("CHEESEBPM");
     lex_nope!("--BPM");
@@ -598,11 +906,73 @@ fn bpm_lexing()
 #[test]
 fn track_lexing()
 {
-    lex_assert!("[0]",  Token::Track(0));
-    lex_assert!("[1]",  Token::Track(1));
-    lex_assert!("[2]",  Token::Track(2));
-    lex_assert!("[9]",  Token::Track(9));
-    lex_assert!("[12]", Token::Track(12));
+    lex_assert!("[0]",  Token::Track(TrackRef::Id(0), TrackAttributes::default()));
+    lex_assert!("[1]",  Token::Track(TrackRef::Id(1), TrackAttributes::default()));
+    lex_assert!("[2]",  Token::Track(TrackRef::Id(2), TrackAttributes::default()));
+    lex_assert!("[9]",  Token::Track(TrackRef::Id(9), TrackAttributes::default()));
+    lex_assert!("[12]", Token::Track(TrackRef::Id(12), TrackAttributes::default()));
+    lex_assert!("[soprano]", Token::Track(TrackRef::Part("soprano".to_string()), TrackAttributes::default()));
+    lex_assert!("[Bass2]", Token::Track(TrackRef::Part("Bass2".to_string()), TrackAttributes::default()));
+    lex_nope!("[2nd]");
+    lex_nope!("[]");
+}
+
+#[test]
+fn voice_lexing()
+{
+    lex_assert!("VOICE paul",  Token::Voice(Voice::Paul));
+    lex_assert!("VOICE betty", Token::Voice(Voice::Betty));
+    lex_assert!("VOICE  kit",  Token::Voice(Voice::Kit));
+    lex_nope!("VOICE bob");
+    lex_nope!("VOICE Paul");
+
+    let lits = read_literals_from_multiline_string("[2] VOICE wendy | a |", "").unwrap();
+    assert_eq!(lits[1].literal, "VOICE wendy");
+    let err = lex_multiline_string("VOICE gandalf").unwrap_err();
+    assert!(matches!(err, CompileError::UnknownVoice(_)));
+}
+
+#[test]
+fn part_lexing()
+{
+    lex_assert!("PART soprano = [1]", Token::Part("soprano".to_string(), 1));
+    lex_assert!("PART alto=[2]", Token::Part("alto".to_string(), 2));
+    lex_nope!("PART 1 = [1]");
+    lex_nope!("PART tenor = [x]");
+
+    let lits = read_literals_from_multiline_string("PART bass = [4]\n[bass pan=0.2] | a |", "").unwrap();
+    assert_eq!(lits[0].literal, "PART bass = [4]");
+    assert_eq!(lits[2].literal, "[bass pan=0.2]");
+    assert_eq!(lex_literal(&lits[2].literal), Some(Token::Track(TrackRef::Part("bass".to_string()),
+        TrackAttributes { pan: Some(0.2), ..Default::default() })));
+}
+
+#[test]
+fn track_header_lexing()
+{
+    lex_assert!("[1 gain=-3dB pan=0.3]", Token::Track(TrackRef::Id(1), TrackAttributes
+    {
+        gain_db: Some(-3.0),
+        pan: Some(0.3)
+    }));
+    lex_assert!("[2 pan=-1]", Token::Track(TrackRef::Id(2), TrackAttributes { pan: Some(-1.0), ..Default::default() }));
+    lex_assert!("[3 gain=+1.5]", Token::Track(TrackRef::Id(3), TrackAttributes { gain_db: Some(1.5), ..Default::default() }));
+
+    lex_nope!("[1 pan=2]");
+    lex_nope!("[1 pan=nan]");
+    lex_nope!("[1 gain=loud]");
+    lex_nope!("[1 gain=-3dB gain=-6dB]");
+    lex_nope!("[1 reverb=0.5]");
+    lex_nope!("[1 alto]");
+    // tracks are named with PART
+    lex_nope!("[1 \"alto\"]");
+
+    // the header is read as one literal, and a bad one says so
+    let lits = read_literals_from_multiline_string("[1 gain=-3dB pan=-0.5] | a b |", "").unwrap();
+    assert_eq!(lits[0].literal, "[1 gain=-3dB pan=-0.5]");
+    assert_eq!(lits[1].literal, "|");
+    let err = lex_multiline_string("[1 pan=left] | a |").unwrap_err();
+    assert!(matches!(err, CompileError::InvalidTrackHeader(_)));
 }
 
 #[test]
@@ -613,6 +983,36 @@ fn garbage_lexing()
     lex_nope!("...--");
 }
 
+#[test]
+fn multiple_lexing_errors()
+{
+    match lex_multiline_string("| a b$ c |\n| d ^e |")
+    {
+        Err(CompileError::Diagnostics(errors)) =>
+        {
+            let literals : Vec<String> = errors.iter()
+                .map(|e| e.location().unwrap().literal.clone()).collect();
+            assert_eq!(literals, vec!["b$", "^e"]);
+        },
+        other => panic!("expected two diagnostics, got {:?}", other),
+    }
+}
+
+#[test]
+fn chord_lexing()
+{
+    lex_assert!("{", Token::ChordOpen);
+    lex_assert!("}", Token::ChordClose);
+
+    let tokens : Vec<Token> = lex_multiline_string("{1 3 5} ah:2 {C E}")
+        .unwrap().into_iter().map(|(_, t)| t).collect();
+    assert_eq!(tokens[0..5], [Token::ChordOpen, Token::ScaleDegree(1, Accidental::Natural),
+        Token::ScaleDegree(3, Accidental::Natural), Token::ScaleDegree(5, Accidental::Natural),
+        Token::ChordClose]);
+    assert_eq!(tokens[6..10], [Token::ChordOpen, Token::AbsolutePitch(ToneId(13)),
+        Token::AbsolutePitch(ToneId(17)), Token::ChordClose]);
+}
+
 #[test]
 fn section_lexing()
 {
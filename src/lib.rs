@@ -3,7 +3,14 @@
 pub mod types;
 pub mod lexer;
 pub mod moonbase;
+pub mod ratelimit;
+pub mod tts;
+pub mod synth;
 pub mod parser;
 pub mod semantics;
+pub mod mix;
+pub mod align;
 pub mod codegen;
+pub mod calibrate;
+pub mod midi;
 pub mod compiler;
@@ -0,0 +1,783 @@
+use crate::lexer::tone_id_to_pitch_string;
+use crate::semantics::{fan_out_chords, Composition, Section};
+use crate::types::{pulse_units, sample_scale, Accidental, CompileError, CompileResult, DynamicLevel, Measure, Scale, ToneId, MIN_TONE, MAX_TONE};
+use fraction::{Fraction, ToPrimitive};
+use midly::num::{u4, u7, u15, u24, u28};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+pub const TICKS_PER_BEAT: u16 = 480;
+
+// the engine's tone 1 is C2, which is MIDI key 36
+const TONE_KEY_OFFSET: u8 = 35;
+
+pub fn tone_to_key(tone: ToneId) -> u8
+{
+    let ToneId(t) = tone;
+    t + TONE_KEY_OFFSET
+}
+
+// keys outside the engine's range are moved by octaves until they fit
+pub fn key_to_tone(key: u8) -> ToneId
+{
+    let mut t = key as i32 - TONE_KEY_OFFSET as i32;
+    while t < MIN_TONE as i32
+    {
+        t += 12;
+    }
+    while t > MAX_TONE as i32
+    {
+        t -= 12;
+    }
+    ToneId(t as u8)
+}
+
+fn dynamic_to_velocity(level: &DynamicLevel) -> u8
+{
+    match level
+    {
+        DynamicLevel::Pianissimo => 33,
+        DynamicLevel::Piano      => 49,
+        DynamicLevel::Mezzopiano => 64,
+        DynamicLevel::Mezzoforte => 80,
+        DynamicLevel::Forte      => 96,
+        DynamicLevel::Fortissimo => 112,
+    }
+}
+
+fn beats_to_ticks(beats: &Fraction) -> u32
+{
+    (beats * Fraction::from(TICKS_PER_BEAT)).to_f64().unwrap_or(0.0).round() as u32
+}
+
+// owned stand-in for midly's borrowed events, so that lyric and name
+// strings live long enough to be written
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum MidiEvent
+{
+    // note-offs sort first so back-to-back notes don't overlap
+    NoteOff(u8),
+    TrackName(String),
+    Tempo(u32),
+    TimeSignature(u8, u8),
+    Lyric(String),
+    NoteOn(u8, u8),
+}
+
+// in quarter notes, whatever the section's own beat unit is
+fn section_quarters(section: &Section) -> Fraction
+{
+    section.tracks.values().map(|measures|
+    {
+        measures.iter().map(|m| m.count_beats()).sum::<Fraction>()
+    })
+    .max()
+    .unwrap_or(Fraction::from(0)) * section.unit_quarters()
+}
+
+// MIDI tempo is always per quarter note, and only changes in steps, so
+// ramps are approximated with a new tempo every quarter of a beat
+fn tempo_events(section: &Section, start: &Fraction, events: &mut Vec<(u32, MidiEvent)>)
+{
+    let unit = section.unit_quarters();
+    let tick_at = |beat: &Fraction| beats_to_ticks(&(start + *beat * unit));
+    let us_at = |beat: &Fraction| (section.tempo_map.period_at(beat) * 1000.0
+        / unit.to_f64().unwrap_or(1.0)).round() as u32;
+
+    let segments = section.tempo_map.segments();
+    for (i, seg) in segments.iter().enumerate()
+    {
+        let end = segments.get(i + 1).map(|s| s.start);
+        match end
+        {
+            Some(end) if seg.from_bpm != seg.to_bpm =>
+            {
+                let step = Fraction::new(1u64, 4u64);
+                let mut beat = seg.start;
+                while beat < end
+                {
+                    let mid = beat + step / Fraction::from(2);
+                    events.push((tick_at(&beat), MidiEvent::Tempo(us_at(&mid))));
+                    beat += step;
+                }
+            },
+            _ =>
+            {
+                events.push((tick_at(&seg.start), MidiEvent::Tempo(us_at(&seg.start))));
+            }
+        }
+    }
+}
+
+fn conductor_events(comp: &Composition) -> Vec<(u32, MidiEvent)>
+{
+    let mut events = vec![(0, MidiEvent::TrackName("regolith".to_string()))];
+    let mut start = Fraction::from(0);
+    for section in &comp.sections
+    {
+        let tick = beats_to_ticks(&start);
+        tempo_events(section, &start, &mut events);
+        if let Some((_, (numer, denom))) = &section.time_signature
+        {
+            events.push((tick, MidiEvent::TimeSignature(*numer, denom.trailing_zeros() as u8)));
+        }
+        start += section_quarters(section);
+    }
+    events
+}
+
+fn track_events(comp: &Composition, track_id: u32) -> Vec<(u32, MidiEvent)>
+{
+    let name = comp.part_name(track_id).cloned().unwrap_or(format!("Track {}", track_id));
+    let mut events = vec![(0, MidiEvent::TrackName(name))];
+    let mut start = Fraction::from(0);
+    for section in &comp.sections
+    {
+        let dynamics = section.dynamics.get(&track_id);
+        let unit = section.unit_quarters();
+        let tick_at = |beat: &Fraction| beats_to_ticks(&(start + *beat * unit));
+        let measures = section.tracks.get(&track_id).map(|m| m.as_slice()).unwrap_or(&[]);
+        for (voice, measures) in fan_out_chords(measures).iter().enumerate()
+        {
+            let mut notes = vec![];
+            let mut cursor = Fraction::from(0);
+            let mut tied = false;
+            for n in measures.iter().flat_map(|m: &Measure| m.notes.iter())
+            {
+                let begin = tick_at(&cursor);
+                let offset = cursor;
+                cursor += n.note.beats;
+                let continues = tied;
+                tied = n.note.tie;
+                if n.note.prefix == "_"
+                {
+                    continue;
+                }
+                let key = tone_to_key(n.tone_id);
+                if continues
+                {
+                    // a tied note just pushes back the previous note-off
+                    if let Some((tick, MidiEvent::NoteOff(_))) = notes.last_mut()
+                    {
+                        *tick = tick_at(&cursor);
+                        continue;
+                    }
+                }
+                // the lyric is carried once, by the chord's first voice
+                if voice == 0
+                {
+                    notes.push((begin, MidiEvent::Lyric(format!("{}{}", n.note.prefix, n.note.suffix))));
+                }
+                let velocity = match dynamics
+                {
+                    Some(d) => d.interpolate(&offset, &|l| dynamic_to_velocity(l) as f64).round() as u8,
+                    None => dynamic_to_velocity(&section.dynamic),
+                };
+                notes.push((begin, MidiEvent::NoteOn(key, velocity)));
+                notes.push((tick_at(&cursor), MidiEvent::NoteOff(key)));
+            }
+            events.extend(notes);
+        }
+        start += section_quarters(section);
+    }
+    events
+}
+
+fn to_track_events(events: &mut Vec<(u32, MidiEvent)>, channel: u8) -> Vec<TrackEvent<'_>>
+{
+    events.sort();
+
+    let channel = u4::new(channel);
+    let mut last = 0;
+    let mut track: Vec<TrackEvent> = events.iter().map(|(tick, event)|
+    {
+        let kind = match event
+        {
+            MidiEvent::TrackName(name) => TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+            MidiEvent::Tempo(us) => TrackEventKind::Meta(MetaMessage::Tempo(u24::new(*us))),
+            MidiEvent::TimeSignature(numer, denom) =>
+                TrackEventKind::Meta(MetaMessage::TimeSignature(*numer, *denom, 24, 8)),
+            MidiEvent::Lyric(text) => TrackEventKind::Meta(MetaMessage::Lyric(text.as_bytes())),
+            MidiEvent::NoteOn(key, vel) => TrackEventKind::Midi
+            {
+                channel,
+                message: MidiMessage::NoteOn { key: u7::new(*key), vel: u7::new(*vel) }
+            },
+            MidiEvent::NoteOff(key) => TrackEventKind::Midi
+            {
+                channel,
+                message: MidiMessage::NoteOff { key: u7::new(*key), vel: u7::new(0) }
+            },
+        };
+        let delta = tick - last;
+        last = *tick;
+        TrackEvent { delta: u28::new(delta), kind }
+    })
+    .collect();
+
+    track.push(TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+    track
+}
+
+fn track_ids(comp: &Composition) -> Vec<u32>
+{
+    let mut ids : Vec<u32> = comp.sections.iter().flat_map(|s| s.tracks.keys().cloned()).collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+// Type 1 file: a conductor track carrying tempo and meter, followed by
+// one MIDI track per regolith track.
+pub fn composition_to_midi(comp: &Composition) -> CompileResult<Vec<u8>>
+{
+    let mut all_events = vec![conductor_events(comp)];
+    for track_id in track_ids(comp)
+    {
+        all_events.push(track_events(comp, track_id));
+    }
+
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(u15::new(TICKS_PER_BEAT))));
+
+    // channel 10 (index 9) is reserved for percussion
+    let channels = (0..16u8).filter(|c| *c != 9).cycle();
+    for (events, channel) in all_events.iter_mut().zip(std::iter::once(0).chain(channels))
+    {
+        smf.tracks.push(to_track_events(events, channel));
+    }
+
+    let mut bytes = vec![];
+    smf.write_std(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub fn write_midi(comp: &Composition, out: &Path) -> CompileResult<()>
+{
+    std::fs::write(out, composition_to_midi(comp)?)?;
+    println!("Writing MIDI: {}", out.display());
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct ImportedNote
+{
+    start: u32,
+    end: u32,
+    key: u8,
+    lyric: Option<String>
+}
+
+#[derive(Debug, Clone)]
+enum StaffElement
+{
+    Note(ToneId, String),
+    Rest(String),
+    // a tempo mark, written ahead of the note it starts at
+    Tempo(String)
+}
+
+const MEASURES_PER_LINE: usize = 4;
+
+fn duration_suffix(beats: Fraction) -> String
+{
+    let numer = *beats.numer().unwrap_or(&1);
+    let denom = *beats.denom().unwrap_or(&1);
+    match (numer, denom)
+    {
+        (1, 1) => "".to_string(),
+        (n, 1) => format!(":{}", n),
+        (1, d) => format!("/{}", d),
+        (n, d) => format!(":{}/{}", n, d),
+    }
+}
+
+fn lyric_to_syllable(lyric: &Option<String>) -> String
+{
+    let syllable : String = lyric.as_deref().unwrap_or("").to_lowercase().chars()
+        .filter(|c| c.is_ascii_lowercase() || *c == '.')
+        .collect();
+    if syllable.is_empty() { ".".to_string() } else { syllable }
+}
+
+fn pitch_token(tone: ToneId, key: Option<&Scale>) -> String
+{
+    if let Some(scale) = key
+    {
+        // out-of-key tones are spelled as a sharpened degree
+        let degrees = scale.steps.len() as u8 * 4;
+        for (accidental, mark) in [(Accidental::Natural, ""), (Accidental::Sharp, "#")]
+        {
+            if let Some(d) = (1..=degrees).find(|d| sample_scale(scale, *d, accidental) == Some(tone))
+            {
+                return format!("{}{}", d, mark);
+            }
+        }
+    }
+    tone_id_to_pitch_string(tone).unwrap_or_else(|| "C3".to_string())
+}
+
+// splits a span at bar lines, since every measure has to add up on its
+// own, and at tempo marks, which are written where they fall; `make` is
+// told whether the piece continues past the split
+fn push_span(measures: &mut [Vec<StaffElement>], from: u64, to: u64, measure_units: u64, grid: u64,
+    marks: &[(u64, u16)], make: &dyn Fn(String, bool) -> StaffElement)
+{
+    let mut cursor = from;
+    while cursor < to
+    {
+        let bar = cursor / measure_units;
+        if let Some((_, bpm)) = marks.iter().find(|(at, _)| *at == cursor)
+        {
+            measures[bar as usize].push(StaffElement::Tempo(format!("{}BPM", bpm)));
+        }
+        let next_mark = marks.iter().map(|(at, _)| *at).filter(|at| *at > cursor).min();
+        let stop = to.min((bar + 1) * measure_units).min(next_mark.unwrap_or(u64::MAX));
+        let duration = duration_suffix(Fraction::new(stop - cursor, grid));
+        measures[bar as usize].push(make(duration, stop < to));
+        cursor = stop;
+    }
+}
+
+fn read_midi_notes(smf: &Smf) -> BTreeMap<u8, Vec<ImportedNote>>
+{
+    let mut channels: BTreeMap<u8, Vec<ImportedNote>> = BTreeMap::new();
+
+    for track in &smf.tracks
+    {
+        let mut tick = 0;
+        let mut lyrics: HashMap<u32, String> = HashMap::new();
+        let mut active: HashMap<(u8, u8), u32> = HashMap::new();
+        let mut finished: Vec<(u8, ImportedNote)> = vec![];
+
+        for event in track
+        {
+            tick += event.delta.as_int();
+            let (channel, message) = match event.kind
+            {
+                TrackEventKind::Meta(MetaMessage::Lyric(text)) =>
+                {
+                    lyrics.insert(tick, String::from_utf8_lossy(text).to_string());
+                    continue;
+                },
+                TrackEventKind::Midi { channel, message } => (channel.as_int(), message),
+                _ => continue,
+            };
+
+            match message
+            {
+                MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 =>
+                {
+                    active.insert((channel, key.as_int()), tick);
+                },
+                MidiMessage::NoteOn { key, .. } |
+                MidiMessage::NoteOff { key, .. } =>
+                {
+                    if let Some(start) = active.remove(&(channel, key.as_int()))
+                    {
+                        finished.push((channel, ImportedNote { start, end: tick, key: key.as_int(), lyric: None }));
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        for (channel, mut note) in finished
+        {
+            note.lyric = lyrics.get(&note.start).cloned();
+            channels.entry(channel).or_default().push(note);
+        }
+    }
+
+    channels
+}
+
+// Generates a markdown document containing one regolith block. Note
+// timings are snapped to `grid` subdivisions of a beat; chords within a
+// channel are reduced to their highest note. Tempo changes become inline
+// marks on the first track, but only the first time signature is kept.
+pub fn midi_to_regolith(bytes: &[u8], title: &str, key: Option<&Scale>, grid: u32) -> CompileResult<String>
+{
+    let smf = Smf::parse(bytes).map_err(|e|
+        CompileError::Generic(format!("Failed to parse MIDI file: {}", e)))?;
+
+    let tpb = match smf.header.timing
+    {
+        Timing::Metrical(t) => t.as_int() as u64,
+        Timing::Timecode(..) => return Err(CompileError::Generic(
+            "Timecode-based MIDI files are not supported".to_string())),
+    };
+
+    let mut tempos: Vec<(u32, u32)> = vec![];
+    let mut time_signatures: Vec<(u32, (u8, u8))> = vec![];
+    for track in &smf.tracks
+    {
+        let mut tick = 0;
+        for event in track
+        {
+            tick += event.delta.as_int();
+            match event.kind
+            {
+                TrackEventKind::Meta(MetaMessage::Tempo(us)) => tempos.push((tick, us.as_int())),
+                // a time signature regolith can't write is ignored
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numer, denom, _, _)) =>
+                {
+                    if let Some(d) = 1u8.checked_shl(denom as u32).filter(|_| numer > 0)
+                    {
+                        time_signatures.push((tick, (numer, d)));
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+    tempos.sort_by_key(|(tick, _)| *tick);
+    time_signatures.sort_by_key(|(tick, _)| *tick);
+
+    let (numer, denom) = time_signatures.first().map_or((4, 4), |(_, ts)| *ts);
+    let pulse = pulse_units(&(numer, denom)) as f64;
+    let to_bpm = |us: u32| (60_000_000.0 / us as f64 * denom as f64 / 4.0 / pulse).round() as u16;
+
+    // a regolith beat is one unit of the time signature's denominator
+    let grid = grid.max(1) as u64;
+    let beat_ticks = (tpb * 4 / denom as u64).max(1);
+    let quantize = |tick: u32| (tick as u64 * grid + beat_ticks / 2) / beat_ticks;
+    let measure_units = numer as u64 * grid;
+
+    // the tempo in force at the start goes in the header, and every later
+    // change that lands on the grid becomes a mark
+    let mut bpm = to_bpm(500_000);
+    let mut tempo_marks: Vec<(u64, u16)> = vec![];
+    for (tick, us) in &tempos
+    {
+        let at = quantize(*tick);
+        if at == 0
+        {
+            bpm = to_bpm(*us);
+            continue;
+        }
+        // of several changes snapped to the same place, the last wins
+        tempo_marks.retain(|(t, _)| *t != at);
+        tempo_marks.push((at, to_bpm(*us)));
+    }
+    let mut current = bpm;
+    tempo_marks.retain(|(_, b)| std::mem::replace(&mut current, *b) != *b);
+
+    let mut voices: Vec<(u8, Vec<(u64, u64, ToneId, String)>)> = vec![];
+    for (channel, mut notes) in read_midi_notes(&smf)
+    {
+        notes.sort_by_key(|n| (n.start, std::cmp::Reverse(n.key)));
+        let mut mono: Vec<(u64, u64, ToneId, String)> = vec![];
+        for n in notes
+        {
+            let start = quantize(n.start);
+            let end = quantize(n.end).max(start + 1);
+            if let Some(last) = mono.last_mut()
+            {
+                if start <= last.0
+                {
+                    continue;
+                }
+                last.1 = last.1.min(start);
+            }
+            mono.push((start, end, key_to_tone(n.key), lyric_to_syllable(&n.lyric)));
+        }
+        voices.push((channel, mono));
+    }
+
+    let end = voices.iter().filter_map(|(_, v)| v.last().map(|n| n.1)).max().unwrap_or(0);
+    let measure_count = ((end + measure_units - 1) / measure_units).max(1);
+    let total = measure_count * measure_units;
+
+    let mut staves = vec![];
+    for (i, (channel, notes)) in voices.iter().enumerate()
+    {
+        // tempo marks apply to every track, so the first one carries them
+        let marks = if i == 0 { &tempo_marks[..] } else { &[] };
+        let mut measures: Vec<Vec<StaffElement>> = vec![vec![]; measure_count as usize];
+        let mut cursor = 0;
+        for (start, end, tone, syllable) in notes
+        {
+            push_span(&mut measures, cursor, *start, measure_units, grid, marks,
+                &|d, _| StaffElement::Rest(format!("-{}", d)));
+            push_span(&mut measures, *start, *end, measure_units, grid, marks,
+                &|d, tie| StaffElement::Note(*tone, format!("{}{}{}", syllable, d, if tie { "~" } else { "" })));
+            cursor = *end;
+        }
+        push_span(&mut measures, cursor, total, measure_units, grid, marks,
+            &|d, _| StaffElement::Rest(format!("-{}", d)));
+        staves.push((*channel as u32 + 1, measures));
+    }
+
+    let mut body = vec![];
+    for line in 0..(measure_count as usize).div_ceil(MEASURES_PER_LINE)
+    {
+        for (track_id, measures) in &staves
+        {
+            let mut tokens = vec![format!("[{}]", track_id), "|".to_string()];
+            let mut current = None;
+            for measure in measures.iter().skip(line * MEASURES_PER_LINE).take(MEASURES_PER_LINE)
+            {
+                for element in measure
+                {
+                    match element
+                    {
+                        StaffElement::Note(tone, text) =>
+                        {
+                            if current != Some(*tone)
+                            {
+                                tokens.push(pitch_token(*tone, key));
+                                current = Some(*tone);
+                            }
+                            tokens.push(text.clone());
+                        },
+                        StaffElement::Rest(text) |
+                        StaffElement::Tempo(text) => tokens.push(text.clone()),
+                    }
+                }
+                tokens.push("|".to_string());
+            }
+            body.push(tokens.join(" "));
+        }
+        body.push("".to_string());
+    }
+
+    let scale = key.map(|s| format!(" {}", s.name)).unwrap_or_default();
+    let note = if time_signatures.iter().any(|(_, ts)| *ts != (numer, denom))
+    {
+        format!(" Later time signature changes were dropped; everything is in {}/{}.", numer, denom)
+    }
+    else
+    {
+        String::new()
+    };
+
+    Ok(format!("# {}\n\nImported from MIDI by midi2rego.{}\n\n```regolith\n{}BPM {}/{}{}\n\n{}```\n",
+        title, note, bpm, numer, denom, scale, body.join("\n")))
+}
+
+#[cfg(test)]
+fn note_summary(comp: &Composition, track_id: u32) -> Vec<(ToneId, Fraction, bool)>
+{
+    comp.sections.iter()
+        .filter_map(|s| s.tracks.get(&track_id))
+        .flat_map(|m| m.iter())
+        .flat_map(|m| m.notes.iter())
+        .map(|n| (n.tone_id, n.note.beats, n.note.prefix == "_"))
+        .filter(|(_, _, rest)| !rest)
+        .collect()
+}
+
+#[test]
+fn midi_round_trip()
+{
+    let source = "90BPM 4/4\n[1] | C ah:2 E oh-n F# . | G .:4 |\n[2] | - C2 ./2 ./2 -:2 | D2 .:3 - |";
+    let comp = crate::semantics::semantics_of(source).unwrap();
+    let bytes = composition_to_midi(&comp).unwrap();
+
+    let cmajor = Scale { name: "CMAJOR".to_string(), ..Scale::cmajor() };
+    for key in [None, Some(cmajor)]
+    {
+        let markdown = midi_to_regolith(&bytes, "round trip", key.as_ref(), 4).unwrap();
+        assert!(markdown.contains("90BPM 4/4"));
+
+        let path = Path::new("/tmp/regolith-midi-round-trip.md");
+        std::fs::write(path, &markdown).unwrap();
+        let tokens = crate::lexer::lex_markdown(path).unwrap();
+        let tree = crate::parser::parse_to_ast(&tokens).unwrap();
+        let imported = crate::semantics::do_semantics(&tree).unwrap();
+
+        assert_eq!(note_summary(&imported, 1), note_summary(&comp, 1));
+        assert_eq!(note_summary(&imported, 2), note_summary(&comp, 2));
+    }
+}
+
+#[test]
+fn midi_import_splits_at_bars()
+{
+    // no time signature here, so the note is free to run past beat 4
+    let comp = crate::semantics::semantics_of("[1] | - - - ah:2 -:3 |").unwrap();
+    let bytes = composition_to_midi(&comp).unwrap();
+    let markdown = midi_to_regolith(&bytes, "split", None, 4).unwrap();
+    assert!(markdown.contains("[1] | -:3 C3 ah~ | ah -:3 |"));
+}
+
+#[test]
+fn midi_import_tempo_changes()
+{
+    let source = "60BPM 4/4\n[1] | ah . . . | 90BPM oh . . 45BPM . |\n[2] | - - oh:2 | oh:4 |";
+    let comp = crate::semantics::semantics_of(source).unwrap();
+    let markdown = midi_to_regolith(&composition_to_midi(&comp).unwrap(), "tempo", None, 4).unwrap();
+    assert!(markdown.contains("60BPM 4/4"));
+    assert!(markdown.contains("| 90BPM oh . . 45BPM . |"));
+
+    let path = Path::new("/tmp/regolith-midi-tempo-changes.md");
+    std::fs::write(path, &markdown).unwrap();
+    let tokens = crate::lexer::lex_markdown(path).unwrap();
+    let tree = crate::parser::parse_to_ast(&tokens).unwrap();
+    let imported = crate::semantics::do_semantics(&tree).unwrap();
+    assert_eq!(imported.sections[0].tempo_map, comp.sections[0].tempo_map);
+    assert_eq!(note_summary(&imported, 2), note_summary(&comp, 2));
+}
+
+#[test]
+fn midi_import_skips_bad_time_signatures()
+{
+    let comp = crate::semantics::semantics_of("3/4 [1] | ah . . |").unwrap();
+    let bytes = composition_to_midi(&comp).unwrap();
+
+    // a 0/4 ahead of the real one, and a change to 2/4 partway through
+    let mut smf = Smf::parse(&bytes).unwrap();
+    smf.tracks[0].insert(0, TrackEvent { delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::TimeSignature(0, 2, 24, 8)) });
+    let end = smf.tracks[0].len() - 1;
+    smf.tracks[0].insert(end, TrackEvent { delta: u28::new(480),
+        kind: TrackEventKind::Meta(MetaMessage::TimeSignature(2, 2, 24, 8)) });
+    let mut edited = vec![];
+    smf.write_std(&mut edited).unwrap();
+
+    let markdown = midi_to_regolith(&edited, "meter", None, 4).unwrap();
+    assert!(markdown.contains("BPM 3/4\n"));
+    assert!(markdown.contains("Later time signature changes were dropped"));
+}
+
+#[test]
+fn midi_compound_meter()
+{
+    let comp = crate::semantics::semantics_of("60BPM 6/8\n[1] | ah . . oh:3 |").unwrap();
+    let smf_bytes = composition_to_midi(&comp).unwrap();
+    let smf = Smf::parse(&smf_bytes).unwrap();
+
+    // a dotted quarter per second is a quarter every two thirds of one
+    assert!(smf.tracks[0].iter().any(|e| e.kind == TrackEventKind::Meta(MetaMessage::Tempo(u24::new(666_667)))));
+
+    let mut tick = 0;
+    let note_ons : Vec<u32> = smf.tracks[1].iter().filter_map(|e|
+    {
+        tick += e.delta.as_int();
+        match e.kind
+        {
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. } => Some(tick),
+            _ => None,
+        }
+    })
+    .collect();
+    assert_eq!(note_ons, vec![0, 240, 480, 720]);
+
+    let markdown = midi_to_regolith(&smf_bytes, "compound", None, 1).unwrap();
+    assert!(markdown.contains("60BPM 6/8"));
+}
+
+#[test]
+fn midi_tempo_ramp_export()
+{
+    let comp = crate::semantics::semantics_of("60BPM 4/4\n[1] | . . RIT . . | 30BPM . . . . |").unwrap();
+    let smf_bytes = composition_to_midi(&comp).unwrap();
+    let smf = Smf::parse(&smf_bytes).unwrap();
+
+    let mut tick = 0;
+    let tempos : Vec<(u32, u32)> = smf.tracks[0].iter().filter_map(|e|
+    {
+        tick += e.delta.as_int();
+        match e.kind
+        {
+            TrackEventKind::Meta(MetaMessage::Tempo(us)) => Some((tick, us.as_int())),
+            _ => None,
+        }
+    })
+    .collect();
+
+    // a steady start, eight steps across the ramp, then the target tempo
+    assert_eq!(tempos.len(), 10);
+    assert_eq!(tempos[0], (0, 1_000_000));
+    assert_eq!(tempos[1], (960, 1_062_500));
+    assert!(tempos[1..9].windows(2).all(|w| w[0].1 < w[1].1));
+    assert_eq!(tempos[9], (1920, 2_000_000));
+}
+
+#[test]
+fn midi_hairpin_velocities()
+{
+    let comp = crate::semantics::semantics_of("PIANO 4/4\n[1] | . CRESC . . FORTE . |").unwrap();
+    let smf_bytes = composition_to_midi(&comp).unwrap();
+    let smf = Smf::parse(&smf_bytes).unwrap();
+
+    let velocities : Vec<u8> = smf.tracks[1].iter().filter_map(|e| match e.kind
+    {
+        TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } => Some(vel.as_int()),
+        _ => None,
+    })
+    .collect();
+    assert_eq!(velocities, vec![49, 49, 73, 96]);
+}
+
+#[test]
+fn midi_chord_export()
+{
+    let comp = crate::semantics::semantics_of("4/4\n[1] | {1 3 5} ah:4 |").unwrap();
+    let smf_bytes = composition_to_midi(&comp).unwrap();
+    let smf = Smf::parse(&smf_bytes).unwrap();
+
+    let keys : Vec<u8> = smf.tracks[1].iter().filter_map(|e| match e.kind
+    {
+        TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+        _ => None,
+    })
+    .collect();
+    assert_eq!(keys, vec![48, 52, 55]);
+
+    let lyrics = smf.tracks[1].iter()
+        .filter(|e| matches!(e.kind, TrackEventKind::Meta(MetaMessage::Lyric(_))))
+        .count();
+    assert_eq!(lyrics, 1);
+}
+
+#[test]
+fn midi_tie_export()
+{
+    let comp = crate::semantics::semantics_of("4/4\n[1] | - - - ah~ | ah -:3 |").unwrap();
+    let smf_bytes = composition_to_midi(&comp).unwrap();
+    let smf = Smf::parse(&smf_bytes).unwrap();
+
+    let note_ons = smf.tracks[1].iter()
+        .filter(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. }))
+        .count();
+    assert_eq!(note_ons, 1);
+
+    let markdown = midi_to_regolith(&smf_bytes, "tie", None, 4).unwrap();
+    assert!(markdown.contains("[1] | -:3 C3 ah~ | ah -:3 |"));
+}
+
+#[test]
+fn midi_export()
+{
+    let source = "100BPM 3/4 FORTE\n[1] | C ah:2 - |\n[2] | E oh-n . . |";
+    let comp = crate::semantics::semantics_of(source).unwrap();
+
+    let bytes = composition_to_midi(&comp).unwrap();
+    let smf = Smf::parse(&bytes).unwrap();
+
+    assert_eq!(smf.header.format, Format::Parallel);
+    assert_eq!(smf.tracks.len(), 3);
+
+    let conductor = &smf.tracks[0];
+    assert!(conductor.iter().any(|e| e.kind == TrackEventKind::Meta(MetaMessage::Tempo(u24::new(600_000)))));
+    assert!(conductor.iter().any(|e| e.kind == TrackEventKind::Meta(MetaMessage::TimeSignature(3, 2, 24, 8))));
+
+    let first = &smf.tracks[1];
+    assert!(first.iter().any(|e| e.kind == TrackEventKind::Meta(MetaMessage::Lyric(b"ah"))));
+    let note_on = first.iter().find_map(|e| match e.kind
+    {
+        TrackEventKind::Midi { message: MidiMessage::NoteOn { key, vel }, .. } => Some((key, vel)),
+        _ => None,
+    });
+    assert_eq!(note_on, Some((u7::new(48), u7::new(96))));
+
+    let ticks : u32 = first.iter()
+        .filter(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOff { .. }, .. }))
+        .map(|e| e.delta.as_int()).sum();
+    assert_eq!(ticks, 2 * TICKS_PER_BEAT as u32);
+
+    let second = &smf.tracks[2];
+    assert_eq!(second.iter().filter(|e| matches!(e.kind, TrackEventKind::Meta(MetaMessage::Lyric(_)))).count(), 3);
+}
@@ -0,0 +1,334 @@
+// Floating point mixing and mastering. Tracks are summed as f32 so any
+// number of voices can line up without wrapping around; the song is only
+// brought back into 16-bit range at the very end, by normalizing it and
+// running it through a limiter.
+
+use std::collections::VecDeque;
+
+// how far ahead the limiter looks for peaks, and how long it takes to
+// recover once they've passed
+const LOOKAHEAD_MS: u32 = 5;
+const RELEASE_MS: u32 = 80;
+
+// loudness measurement blocks, per ITU-R BS.1770
+const BLOCK_MS: u32 = 400;
+const BLOCK_STEP_MS: u32 = 100;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+pub fn to_float(samples: &[i16]) -> Vec<f32>
+{
+    samples.iter().map(|s| *s as f32 / 32768.0).collect()
+}
+
+pub fn to_pcm16(samples: &[f32]) -> Vec<i16>
+{
+    samples.iter().map(|s| (s * 32768.0).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16).collect()
+}
+
+pub fn db_to_gain(db: f32) -> f32
+{
+    10.0f32.powf(db / 20.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalize
+{
+    Off,
+    // to a peak level, in dBFS
+    Peak(f32),
+    // to an integrated loudness, in LUFS
+    Loudness(f32)
+}
+
+impl Normalize
+{
+    // "off", "peak:-1" or "lufs:-16"
+    pub fn parse(s: &str) -> Option<Normalize>
+    {
+        if s == "off"
+        {
+            return Some(Normalize::Off);
+        }
+        let (kind, level) = s.split_once(':')?;
+        let level : f32 = level.parse().ok()?;
+        match kind
+        {
+            "peak" => Some(Normalize::Peak(level)),
+            "lufs" => Some(Normalize::Loudness(level)),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MasterOptions
+{
+    pub normalize: Normalize,
+    // the limiter keeps every sample under this, in dBFS
+    pub ceiling_db: f32
+}
+
+impl Default for MasterOptions
+{
+    fn default() -> Self
+    {
+        MasterOptions { normalize: Normalize::Loudness(-16.0), ceiling_db: -1.0 }
+    }
+}
+
+pub fn peak(samples: &[f32]) -> f32
+{
+    samples.iter().fold(0.0, |m, s| m.max(s.abs()))
+}
+
+// a second order IIR filter, in direct form I
+struct Biquad
+{
+    b: [f64; 3],
+    a: [f64; 2]
+}
+
+impl Biquad
+{
+    fn apply(&self, samples: &[f64]) -> Vec<f64>
+    {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        samples.iter().map(|x|
+        {
+            let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2 - self.a[0] * y1 - self.a[1] * y2;
+            (x2, x1, y2, y1) = (x1, *x, y1, y);
+            y
+        })
+        .collect()
+    }
+}
+
+// the K-weighting curve: a high shelf for the head's acoustic effect, then
+// a high pass that discounts rumble. coefficients are derived for any
+// sample rate the way libebur128 does.
+fn k_weight(samples: &[f32], sample_rate: u32) -> Vec<f64>
+{
+    let fs = sample_rate as f64;
+
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad
+    {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad
+    {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]
+    };
+
+    let input : Vec<f64> = samples.iter().map(|s| *s as f64).collect();
+    high_pass.apply(&shelf.apply(&input))
+}
+
+fn mean_square_to_lufs(ms: f64) -> f64
+{
+    -0.691 + 10.0 * ms.log10()
+}
+
+// splits interleaved samples into one buffer per channel
+fn deinterleave(samples: &[f32], channels: usize) -> Vec<Vec<f32>>
+{
+    (0..channels).map(|c| samples.iter().skip(c).step_by(channels).cloned().collect()).collect()
+}
+
+// gated integrated loudness per BS.1770, or None for silence. the
+// channels' mean squares add up, all weighted equally
+pub fn integrated_loudness(samples: &[f32], channels: usize, sample_rate: u32) -> Option<f32>
+{
+    let weighted : Vec<Vec<f64>> = deinterleave(samples, channels.max(1)).iter()
+        .map(|s| k_weight(s, sample_rate)).collect();
+    let frames = weighted[0].len();
+    let block = (sample_rate * BLOCK_MS / 1000) as usize;
+    let step = (sample_rate * BLOCK_STEP_MS / 1000) as usize;
+
+    let mean_square = |from: usize, to: usize| weighted.iter()
+        .map(|w| w[from..to].iter().map(|x| x * x).sum::<f64>() / (to - from) as f64).sum::<f64>();
+    let blocks : Vec<f64> = if frames <= block
+    {
+        vec![mean_square(0, frames)]
+    }
+    else
+    {
+        (0..=(frames - block) / step).map(|i| mean_square(i * step, i * step + block)).collect()
+    };
+
+    let gated = |threshold: f64| -> Vec<f64>
+    {
+        blocks.iter().cloned().filter(|z| *z > 0.0 && mean_square_to_lufs(*z) > threshold).collect()
+    };
+    let mean = |z: &[f64]| z.iter().sum::<f64>() / z.len() as f64;
+
+    let loud = gated(ABSOLUTE_GATE_LUFS);
+    if loud.is_empty()
+    {
+        return None;
+    }
+    let relative = mean_square_to_lufs(mean(&loud)) + RELATIVE_GATE_LU;
+    let loud = gated(relative.max(ABSOLUTE_GATE_LUFS));
+    Some(mean_square_to_lufs(mean(&loud)) as f32)
+}
+
+// A lookahead peak limiter. The gain comes down over the few ms before
+// any sample that would pass the ceiling, so nothing is clipped and there
+// are no sudden steps, then recovers over the release time. channels
+// share one gain, so the stereo image doesn't shift.
+pub fn limit(samples: &mut [f32], channels: usize, ceiling: f32, sample_rate: u32)
+{
+    let channels = channels.max(1);
+    let lookahead = (sample_rate * LOOKAHEAD_MS / 1000).max(1) as usize;
+    let release_step = 1000.0 / (sample_rate * RELEASE_MS) as f32;
+    let required : Vec<f32> = samples.chunks(channels)
+        .map(|frame| peak(frame))
+        .map(|p| if p > ceiling { ceiling / p } else { 1.0 }).collect();
+    let frames = required.len();
+
+    // the lowest gain needed over the next `lookahead` frames
+    let mut window_min = vec![1.0f32; frames];
+    let mut window : VecDeque<usize> = VecDeque::new();
+    for i in (0..frames).rev()
+    {
+        while window.back().map_or(false, |&j| required[j] >= required[i])
+        {
+            window.pop_back();
+        }
+        window.push_back(i);
+        if window[0] >= i + lookahead
+        {
+            window.pop_front();
+        }
+        window_min[i] = required[window[0]];
+    }
+
+    // averaging the minima ramps into each reduction; every minimum in the
+    // average covers the current sample, so it never exceeds what's needed
+    let mut sum = lookahead as f64;
+    let mut gain = 1.0f32;
+    for (i, frame) in samples.chunks_mut(channels).enumerate()
+    {
+        sum += window_min[i] as f64 - if i >= lookahead { window_min[i - lookahead] as f64 } else { 1.0 };
+        let ramped = (sum / lookahead as f64) as f32;
+        gain = ramped.min(gain + release_step).min(required[i]);
+        frame.iter_mut().for_each(|s| *s = (*s * gain).clamp(-ceiling, ceiling));
+    }
+}
+
+// normalizes and limits interleaved audio in place
+pub fn master(samples: &mut [f32], channels: usize, sample_rate: u32, options: &MasterOptions)
+{
+    let gain = match options.normalize
+    {
+        Normalize::Off => 1.0,
+        Normalize::Peak(db) => match peak(samples)
+        {
+            p if p > 0.0 => db_to_gain(db) / p,
+            _ => 1.0,
+        },
+        Normalize::Loudness(lufs) => integrated_loudness(samples, channels, sample_rate)
+            .map_or(1.0, |measured| db_to_gain(lufs - measured)),
+    };
+
+    samples.iter_mut().for_each(|s| *s *= gain);
+    limit(samples, channels, db_to_gain(options.ceiling_db), sample_rate);
+}
+
+#[cfg(test)]
+fn sine(freq: f32, amplitude: f32, seconds: f32, sample_rate: u32) -> Vec<f32>
+{
+    (0..(seconds * sample_rate as f32) as usize)
+        .map(|i| (i as f32 / sample_rate as f32 * freq * std::f32::consts::TAU).sin() * amplitude)
+        .collect()
+}
+
+#[test]
+fn loudness_measurement()
+{
+    // a full scale 997 Hz tone in one channel measures -3.01 LUFS
+    let tone = sine(997.0, 1.0, 5.0, 48000);
+    assert!((integrated_loudness(&tone, 1, 48000).unwrap() + 3.01).abs() < 0.05);
+
+    // and the weighting holds at the engine's rate
+    let tone = sine(997.0, 0.5, 5.0, 11025);
+    assert!((integrated_loudness(&tone, 1, 11025).unwrap() + 9.03).abs() < 0.1);
+
+    // silence is gated out entirely, and barely drags down what's loud;
+    // only the blocks straddling the end of the tone count against it
+    assert_eq!(integrated_loudness(&vec![0.0; 48000], 1, 48000), None);
+    let mut padded = sine(997.0, 1.0, 5.0, 48000);
+    padded.extend(vec![0.0; 48000 * 5]);
+    assert!((integrated_loudness(&padded, 1, 48000).unwrap() + 3.01).abs() < 0.2);
+
+    // the same tone in both channels is twice the power
+    let stereo : Vec<f32> = sine(997.0, 1.0, 5.0, 48000).iter().flat_map(|s| [*s, *s]).collect();
+    assert!((integrated_loudness(&stereo, 2, 48000).unwrap() + 0.0).abs() < 0.05);
+}
+
+#[test]
+fn limiting()
+{
+    let ceiling = db_to_gain(-1.0);
+
+    // quiet material passes through untouched
+    let quiet = sine(440.0, 0.5, 1.0, 11025);
+    let mut limited = quiet.clone();
+    limit(&mut limited, 1, ceiling, 11025);
+    assert_eq!(limited, quiet);
+
+    // three voices summed well past full scale come out under the ceiling
+    let mut loud : Vec<f32> = sine(440.0, 0.9, 1.0, 11025).iter()
+        .zip(sine(550.0, 0.9, 1.0, 11025)).zip(sine(660.0, 0.9, 1.0, 11025))
+        .map(|((a, b), c)| a + b + c).collect();
+    assert!(peak(&loud) > 2.0);
+    limit(&mut loud, 1, ceiling, 11025);
+    assert!(peak(&loud) <= ceiling);
+    assert!(peak(&loud) > ceiling * 0.9);
+
+    // without steps in the gain
+    let mut burst = sine(440.0, 0.5, 1.0, 11025);
+    burst[5000] = 2.0;
+    limit(&mut burst, 1, ceiling, 11025);
+    let gains : Vec<f32> = burst.iter().zip(sine(440.0, 0.5, 1.0, 11025)).enumerate()
+        .filter(|(i, (_, orig))| *i != 5000 && orig.abs() > 0.1).map(|(_, (b, orig))| b / orig).collect();
+    assert!(gains.iter().any(|g| *g < 0.5));
+    assert!(gains.windows(2).all(|w| (w[0] - w[1]).abs() < 0.2));
+
+    // a peak in one channel turns both down together
+    let mut stereo : Vec<f32> = sine(440.0, 0.5, 1.0, 11025).iter().flat_map(|s| [*s * 3.0, *s]).collect();
+    limit(&mut stereo, 2, ceiling, 11025);
+    assert!(peak(&stereo) <= ceiling);
+    assert!(stereo.chunks(2).all(|f| f[1] == 0.0 || (f[0] / f[1] - 3.0).abs() < 0.01));
+}
+
+#[test]
+fn mastering()
+{
+    let mut tone = sine(997.0, 0.1, 2.0, 11025);
+    master(&mut tone, 1, 11025, &MasterOptions { normalize: Normalize::Peak(-3.0), ceiling_db: -1.0 });
+    assert!((peak(&tone) - db_to_gain(-3.0)).abs() < 0.001);
+
+    let mut tone = sine(997.0, 0.1, 2.0, 11025);
+    master(&mut tone, 1, 11025, &MasterOptions::default());
+    assert!((integrated_loudness(&tone, 1, 11025).unwrap() + 16.0).abs() < 0.05);
+
+    assert_eq!(Normalize::parse("lufs:-14"), Some(Normalize::Loudness(-14.0)));
+    assert_eq!(Normalize::parse("peak:-0.5"), Some(Normalize::Peak(-0.5)));
+    assert_eq!(Normalize::parse("off"), Some(Normalize::Off));
+    assert_eq!(Normalize::parse("rms:-3"), None);
+
+    assert_eq!(to_pcm16(&to_float(&[i16::MIN, -1, 0, 1, i16::MAX])), [i16::MIN, -1, 0, 1, i16::MAX]);
+    assert_eq!(to_pcm16(&[3.0, -3.0]), [i16::MAX, i16::MIN]);
+}
@@ -1,12 +1,84 @@
-use reqwest::{Request, StatusCode};
+use regex_macro::regex;
 
-#[allow(warnings)]
-
-use crate::types::{CompileError, CompileResult, ToneId};
-use std::fs::File;
+use crate::types::{CompileError, CompileResult, ToneId, Voice};
+use crate::tts::TtsBackend;
+#[cfg(test)]
+use crate::tts::StubBackend;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
-use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// the engine renders 16-bit mono audio at this rate
+pub const SAMPLE_RATE: u32 = 11025;
+
+// the TTS engine adds about 4 seconds worth of audio for every 60
+// notes, regardless of BPM; 4000 ms / 60 notes ~= 67 ms per note.
+// however this doesn't apply to rests. whatever drift remains after
+// this is measured and corrected in align.
+pub const NOTE_BIAS_MS: i32 = 67;
+
+// trailing silence the engine appends to every render
+pub const TAIL_SAMPLES: usize = 5500;
+
+// How a backend pads what it renders. The constants above were measured
+// by hand against the public service; `rc calibrate` fits them for
+// whichever backend is in use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing
+{
+    pub note_bias_ms: i32,
+    pub tail_samples: usize
+}
+
+impl Default for Timing
+{
+    fn default() -> Self
+    {
+        Timing { note_bias_ms: NOTE_BIAS_MS, tail_samples: TAIL_SAMPLES }
+    }
+}
+
+impl Timing
+{
+    pub fn to_config(&self) -> String
+    {
+        format!("note_bias_ms = {}\ntail_samples = {}\n", self.note_bias_ms, self.tail_samples)
+    }
+
+    // reads "key = value" lines; missing keys keep their defaults
+    pub fn from_config(text: &str) -> CompileResult<Timing>
+    {
+        let mut timing = Timing::default();
+        for (i, line) in text.lines().enumerate()
+        {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#')
+            {
+                continue;
+            }
+
+            let bad = || CompileError::Generic(format!("timing config line {}: \"{}\"", i + 1, line));
+            let (key, value) = line.split_once('=').ok_or_else(bad)?;
+            match key.trim()
+            {
+                "note_bias_ms" => timing.note_bias_ms = value.trim().parse().map_err(|_| bad())?,
+                "tail_samples" => timing.tail_samples = value.trim().parse().map_err(|_| bad())?,
+                _ => return Err(bad()),
+            }
+        }
+        Ok(timing)
+    }
+}
+
+#[test]
+fn timing_config()
+{
+    let timing = Timing { note_bias_ms: 58, tail_samples: 4410 };
+    assert_eq!(Timing::from_config(&timing.to_config()).unwrap(), timing);
+    assert_eq!(Timing::from_config("# nothing fitted yet\n").unwrap(), Timing::default());
+    assert_eq!(Timing::from_config("tail_samples=12").unwrap().tail_samples, 12);
+    assert!(Timing::from_config("note_bias_ms = soon").is_err());
+    assert!(Timing::from_config("tempo = 120").is_err());
+}
 
 #[derive(Debug)]
 pub struct MoonbaseNote
@@ -42,13 +114,9 @@ fn filename_hashing()
     );
 }
 
-pub fn to_moonbase_str(mbn: &MoonbaseNote) -> String
+pub fn to_moonbase_str(mbn: &MoonbaseNote, timing: &Timing) -> String
 {
-    // the TTS engine adds about 4 seconds worth of audio for every 60
-    // notes, regardless of BPM; 4000 ms / 60 notes ~= 67 ms per note.
-    // however this doesn't apply to rests.
-
-    let bias = 67;
+    let bias = timing.note_bias_ms;
     let mut ms = mbn.dur_ms;
     if mbn.prefix != "_" && mbn.dur_ms > bias
     {
@@ -98,7 +166,7 @@ fn moonbase_strings()
         suffix: "".to_string(),
         dur_ms: 40,
         tone_id: ToneId(19)
-    }));
+    }, &Timing::default()));
 
     assert_eq!("[du<53,10>th]", to_moonbase_str(&MoonbaseNote
     {
@@ -106,7 +174,7 @@ fn moonbase_strings()
         suffix: "th".to_string(),
         dur_ms: 120,
         tone_id: ToneId(10)
-    }));
+    }, &Timing::default()));
 
     assert_eq!("[uh<26,28>wf]", to_moonbase_str(&MoonbaseNote
     {
@@ -114,18 +182,74 @@ fn moonbase_strings()
         suffix: "wf".to_string(),
         dur_ms: 93,
         tone_id: ToneId(28)
-    }));
+    }, &Timing::default()));
 }
 
-pub fn create_dir(p: &Path) -> Result<(), std::io::Error>
+// switches the engine to a speaker for everything after it; no prefix
+// leaves the engine's default, paul
+pub fn speaker_prefix(voice: Option<Voice>) -> String
 {
-    if !p.exists()
+    let code = match voice
     {
-        std::fs::create_dir(p)
-    }
-    else
+        None => return String::new(),
+        Some(Voice::Paul)   => 'p',
+        Some(Voice::Harry)  => 'h',
+        Some(Voice::Frank)  => 'f',
+        Some(Voice::Dennis) => 'd',
+        Some(Voice::Betty)  => 'b',
+        Some(Voice::Ursula) => 'u',
+        Some(Voice::Wendy)  => 'w',
+        Some(Voice::Rita)   => 'r',
+        Some(Voice::Kit)    => 'k',
+    };
+    format!("[:n{}]", code)
+}
+
+pub fn parse_moonbase_str(moonbase: &str) -> Vec<MoonbaseNote>
+{
+    let note_re = regex!(r"\[([^<\[\]]*)<(\d+),(\d+)>([^<\[\]]*)\]");
+
+    note_re.captures_iter(moonbase).filter_map(|cap|
     {
-        Ok(())
+        Some(MoonbaseNote
+        {
+            prefix: cap.get(1)?.as_str().to_string(),
+            suffix: cap.get(4)?.as_str().to_string(),
+            dur_ms: cap.get(2)?.as_str().parse().ok()?,
+            tone_id: ToneId(cap.get(3)?.as_str().parse().ok()?)
+        })
+    })
+    .collect()
+}
+
+#[test]
+fn moonbase_parsing()
+{
+    let notes = parse_moonbase_str("[duw<40,19>][_<500,13>] junk [du<53,10>th]");
+    assert_eq!(notes.len(), 3);
+    assert_eq!(notes[0].prefix, "duw");
+    assert_eq!(notes[0].dur_ms, 40);
+    assert_eq!(notes[0].tone_id, ToneId(19));
+    assert_eq!(notes[1].prefix, "_");
+    assert_eq!(notes[2].suffix, "th");
+    assert_eq!(notes[2].tone_id, ToneId(10));
+
+    assert!(parse_moonbase_str("command error in phoneme").is_empty());
+
+    // a speaker switch isn't a note
+    let prefixed = format!("{}[duw<40,19>]", speaker_prefix(Some(Voice::Betty)));
+    assert_eq!(prefixed, "[:nb][duw<40,19>]");
+    assert_eq!(parse_moonbase_str(&prefixed).len(), 1);
+    assert_eq!(speaker_prefix(None), "");
+}
+
+pub fn create_dir(p: &Path) -> Result<(), std::io::Error>
+{
+    // another render worker may create it between the check and here
+    match std::fs::create_dir(p)
+    {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        res => res
     }
 }
 
@@ -134,7 +258,10 @@ pub enum MoonbaseError
 {
     Generic,
     FileError(std::io::Error),
-    NetworkError(reqwest::Error)
+    NetworkError(reqwest::Error),
+    EngineError(String),
+    // the backend refused the string for its length
+    TooLarge
 }
 
 impl From<std::io::Error> for MoonbaseError
@@ -153,66 +280,67 @@ impl From<reqwest::Error> for MoonbaseError
     }
 }
 
-type MoonbaseResult<T> = Result<T, MoonbaseError>;
-
-pub fn generate_moonbase(moonbase: &str, tmp_dir: &Path) -> MoonbaseResult<PathBuf>
+impl From<hound::Error> for MoonbaseError
 {
-    // TODO
-    let num_attempts = 10;
-    let backoff_dur = Duration::new(4, 0);
+    fn from(error: hound::Error) -> Self
+    {
+        MoonbaseError::EngineError(format!("Hound error: {}", error))
+    }
+}
+
+pub type MoonbaseResult<T> = Result<T, MoonbaseError>;
 
-    let outpath = hashed_fn(moonbase, "wav", tmp_dir);
+pub fn generate_moonbase(backend: &dyn TtsBackend, moonbase: &str, tmp_dir: &Path) -> MoonbaseResult<PathBuf>
+{
+    // renders are cached per backend, since the same string sounds
+    // different depending on which engine produced it
+    let backend_dir = tmp_dir.join(backend.name());
+    let outpath = hashed_fn(moonbase, "wav", &backend_dir);
     if outpath.exists()
     {
         return Ok(outpath);
     }
 
-    let url = format!("http://tts.cyzon.us/tts?text={}", moonbase);
+    create_dir(tmp_dir)?;
+    create_dir(&backend_dir)?;
 
-    for _ in 0..num_attempts
+    // render beside the cache entry and move it into place, so a worker
+    // rendering the same string never sees a half-written file
+    static PARTS: AtomicUsize = AtomicUsize::new(0);
+    let part = outpath.with_extension(format!("part-{}-{}.wav",
+        std::process::id(), PARTS.fetch_add(1, Ordering::Relaxed)));
+    if let Err(e) = backend.render(moonbase, &part)
     {
-        let resp = reqwest::blocking::get(&url)?;
-        if resp.status() == StatusCode::TOO_MANY_REQUESTS
-        {
-            std::thread::sleep(backoff_dur);
-            continue;
-        }
-
-        resp.error_for_status_ref()?;
-
-        create_dir(tmp_dir)?;
-        let mut file = File::create(&outpath)?;
-        let bytes = resp.bytes()?;
-        file.write_all(&bytes)?;
-        println!("Writing audio: {}", outpath.display());
-        return Ok(outpath);
+        let _ = std::fs::remove_file(&part);
+        return Err(e);
     }
-
-    Err(MoonbaseError::Generic)
+    std::fs::rename(&part, &outpath)?;
+    Ok(outpath)
 }
 
 #[test]
 fn moonbase_gen()
 {
     let tmp_dir = Path::new("/tmp/");
+    let backend = StubBackend;
 
     assert_eq!(
-        generate_moonbase("[duw<500,19>] [duw<500,19>]", tmp_dir).unwrap(),
-        Path::new("/tmp/0f4ed7068d8362b1c2dafa2baea51b5d.wav")
+        generate_moonbase(&backend, "[duw<500,19>] [duw<500,19>]", tmp_dir).unwrap(),
+        Path::new("/tmp/stub/0f4ed7068d8362b1c2dafa2baea51b5d.wav")
     );
 
     assert_eq!(
-        generate_moonbase("wefwefw", tmp_dir).unwrap(),
-        Path::new("/tmp/37e838885e9fd07692e5da83e515878e.wav")
+        generate_moonbase(&backend, "wefwefw", tmp_dir).unwrap(),
+        Path::new("/tmp/stub/37e838885e9fd07692e5da83e515878e.wav")
     );
 
     assert_eq!(
-        generate_moonbase("command error in phoneme", tmp_dir).unwrap(),
-        Path::new("/tmp/b1ec37d0fe49d4b46bb7f1ad801ae335.wav")
+        generate_moonbase(&backend, "command error in phoneme", tmp_dir).unwrap(),
+        Path::new("/tmp/stub/b1ec37d0fe49d4b46bb7f1ad801ae335.wav")
     );
 
     assert_eq!(
-        generate_moonbase("[duw<500,19>] [duw<500,19>] command error in phoneme", tmp_dir).unwrap(),
-        Path::new("/tmp/834abde08a1c2303efd64755f2ad84fb.wav")
+        generate_moonbase(&backend, "[duw<500,19>] [duw<500,19>] command error in phoneme", tmp_dir).unwrap(),
+        Path::new("/tmp/stub/834abde08a1c2303efd64755f2ad84fb.wav")
     );
 }
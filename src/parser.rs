@@ -3,6 +3,7 @@ use crate::lexer::{lex_markdown, lex_multiline_string};
 use indoc::indoc;
 use colored::Colorize;
 use std::path::Path;
+use fraction::Fraction;
 
 #[derive(Debug, Clone)]
 pub enum PreambleNode
@@ -27,6 +28,17 @@ pub enum PreambleNode
         literal: Literal,
         ratio: TimeSignature,
     },
+    Part
+    {
+        literal: Literal,
+        name: String,
+        track_id: u32,
+    },
+    Voice
+    {
+        literal: Literal,
+        voice: Voice,
+    },
     Endline(Literal),
 }
 
@@ -46,12 +58,39 @@ pub enum StaffNode
     Track
     {
         literal: Literal,
-        track_id: u32,
+        track: TrackRef,
+        attributes: TrackAttributes,
     },
     ScaleDegree
     {
         literal: Literal,
         degree: u8,
+        accidental: Accidental,
+    },
+    Chord
+    {
+        literal: Literal,
+        pitches: Vec<StaffNode>,
+    },
+    Tempo
+    {
+        literal: Literal,
+        mark: TempoMark,
+    },
+    Dynamic
+    {
+        literal: Literal,
+        mark: DynamicMark,
+    },
+    TimeSignature
+    {
+        literal: Literal,
+        ratio: TimeSignature,
+    },
+    Voice
+    {
+        literal: Literal,
+        voice: Voice,
     },
     MeasureBar
     {
@@ -86,7 +125,8 @@ pub type AST = Vec<SectionNode>;
 
 struct Parser
 {
-    tokens: Vec<(Literal, Token)>
+    tokens: Vec<(Literal, Token)>,
+    errors: Vec<CompileError>
 }
 
 impl Parser
@@ -94,7 +134,7 @@ impl Parser
     fn new(tokens: &Vec<(Literal, Token)>) -> Self
     {
         Parser { tokens: tokens.iter().rev().map(
-            |(l, t)| (l.clone(), t.clone())).collect() }
+            |(l, t)| (l.clone(), t.clone())).collect(), errors: vec![] }
     }
 
     fn peek(&self) -> Option<&(Literal, Token)>
@@ -111,6 +151,28 @@ impl Parser
     {
         return self.tokens.pop()
     }
+
+    fn report(&mut self, error: CompileError)
+    {
+        self.errors.push(error);
+    }
+
+    // after an error, skip ahead to the next measure bar, endline or
+    // section marker so parsing can pick up again from a known state
+    fn synchronize(&mut self)
+    {
+        while let Some((_, token)) = self.peek()
+        {
+            match token
+            {
+                Token::MeasureBar(_, _) |
+                Token::EndRepeat(_) |
+                Token::Endline() |
+                Token::Section(_) => return,
+                _ => { self.take(); },
+            }
+        }
+    }
 }
 
 pub fn parse_to_ast(tokens: &Vec<(Literal, Token)>) -> CompileResult<AST>
@@ -121,17 +183,18 @@ pub fn parse_to_ast(tokens: &Vec<(Literal, Token)>) -> CompileResult<AST>
 
     while let Some((_, _)) = parser.peek()
     {
-        let section = eat_section(&mut parser)?;
-        sections.push(section);
+        if let Some(section) = eat_section(&mut parser)
+        {
+            sections.push(section);
+        }
     }
 
-    Ok(sections)
+    collect_diagnostics(sections, parser.errors)
 }
 
-fn eat_section(parser: &mut Parser) -> CompileResult<SectionNode>
+fn eat_section(parser: &mut Parser) -> Option<SectionNode>
 {
-    let (mut section_literal, section_token) = parser.peek_copy().ok_or(
-        CompileError::GenericSyntax("Encountered EOF while parsing section block".to_string()))?;
+    let (mut section_literal, section_token) = parser.peek_copy()?;
     let section_name = if let Token::Section(ref name) = section_token
     {
         parser.take();
@@ -156,19 +219,30 @@ fn eat_section(parser: &mut Parser) -> CompileResult<SectionNode>
             Token::TimeSignature(_) |
             Token::Scale(_) |
             Token::Tempo(_) |
+            Token::Part(..) |
+            Token::Voice(_) |
             Token::Endline() =>
             {
                 eat_preamble_atomic(parser)
             },
-            Token::Track(_) |
+            Token::Track(..) |
             Token::MeasureBar(_, _) |
+            Token::EndRepeat(_) |
             Token::AbsolutePitch(_) |
-            Token::ScaleDegree(_) |
+            Token::ScaleDegree(_, _) |
+            Token::ChordOpen |
+            Token::ChordClose |
+            Token::TempoRamp(_) |
+            Token::Hairpin(_) |
             Token::Note(_) |
             Token::Section(_) => break,
-        }?;
+        };
 
-        preamble.push(node);
+        match node
+        {
+            Ok(n) => preamble.push(n),
+            Err(e) => parser.report(e),
+        }
     }
 
     preamble = preamble.into_iter().filter(|node|
@@ -186,31 +260,42 @@ fn eat_section(parser: &mut Parser) -> CompileResult<SectionNode>
         let node = match token
         {
             Token::Section(_) => break,
-            Token::Dynamic(_) |
-            Token::Tempo(_) |
             Token::Scale(_) |
+            Token::Part(..) |
             Token::TimeSignature(_) =>
             {
-                if let Some(ref first) = first_staff
+                let error = if let Some(ref first) = first_staff
                 {
-                    Err(CompileError::PreambleOrder(section_literal.clone(), first.clone(), literal))
+                    CompileError::PreambleOrder(section_literal.clone(), first.clone(), literal)
                 }
                 else
                 {
-                    Err(CompileError::GenericSyntax("Expected a staff element".to_string()))
-                }
+                    CompileError::GenericSyntax("Expected a staff element".to_string())
+                };
+                parser.report(error);
+                parser.take();
+                parser.synchronize();
+                None
             },
             Token::Endline() |
             Token::MeasureBar(_, _) |
-            Token::Track(_) |
-            Token::ScaleDegree(_) |
+            Token::EndRepeat(_) |
+            Token::Track(..) |
+            Token::Voice(_) |
+            Token::ScaleDegree(_, _) |
             Token::AbsolutePitch(_) |
+            Token::ChordOpen |
+            Token::ChordClose |
+            Token::Tempo(_) |
+            Token::TempoRamp(_) |
+            Token::Dynamic(_) |
+            Token::Hairpin(_) |
             Token::Note(_) =>
             {
                 first_staff.get_or_insert(literal);
                 eat_measure_block(parser)
             }
-        }?;
+        };
 
         if let Some(n) = node
         {
@@ -218,7 +303,7 @@ fn eat_section(parser: &mut Parser) -> CompileResult<SectionNode>
         }
     }
 
-    Ok(SectionNode { literal: section_literal, name: section_name, preamble, measures })
+    Some(SectionNode { literal: section_literal, name: section_name, preamble, measures })
 }
 
 fn atomic_token_to_staff_node(token: Token, literal: Literal) -> Option<StaffNode>
@@ -226,15 +311,22 @@ fn atomic_token_to_staff_node(token: Token, literal: Literal) -> Option<StaffNod
     match token
     {
         Token::Note(note) => Some(StaffNode::Note{ literal, note }),
-        Token::Track(track_id) => Some(StaffNode::Track{ literal, track_id }),
-        Token::ScaleDegree(degree) => Some(StaffNode::ScaleDegree{ literal, degree }),
+        Token::Track(track, attributes) => Some(StaffNode::Track{ literal, track, attributes }),
+        Token::ScaleDegree(degree, accidental) => Some(StaffNode::ScaleDegree{ literal, degree, accidental }),
         Token::AbsolutePitch(pitch) => Some(StaffNode::AbsolutePitch{ literal, pitch }),
         Token::MeasureBar(close, open) => Some(StaffNode::MeasureBar { literal, close, open }),
+        Token::EndRepeat(_) => Some(StaffNode::MeasureBar { literal, close: true, open: false }),
         Token::Endline() => Some(StaffNode::Endline{ literal }),
-        Token::Tempo(_) |
-        Token::Dynamic(_) |
+        Token::Tempo(bpm) => Some(StaffNode::Tempo{ literal, mark: TempoMark::Set(bpm) }),
+        Token::TempoRamp(ramp) => Some(StaffNode::Tempo{ literal, mark: TempoMark::Ramp(ramp) }),
+        Token::Dynamic(level) => Some(StaffNode::Dynamic{ literal, mark: DynamicMark::Set(level) }),
+        Token::Hairpin(hairpin) => Some(StaffNode::Dynamic{ literal, mark: DynamicMark::Hairpin(hairpin) }),
+        Token::TimeSignature(ratio) => Some(StaffNode::TimeSignature{ literal, ratio }),
+        Token::Voice(voice) => Some(StaffNode::Voice{ literal, voice }),
         Token::Scale(_) |
-        Token::TimeSignature(_) |
+        Token::Part(..) |
+        Token::ChordOpen |
+        Token::ChordClose |
         Token::Section(_) => None
     }
 }
@@ -247,12 +339,19 @@ fn atomic_token_to_preamble_node(token: Token, literal: Literal) -> Option<Pream
         Token::Dynamic(level) => Some(PreambleNode::DynamicLevel{ literal, level }),
         Token::Scale(scale) => Some(PreambleNode::Scale{ literal, scale: scale.clone() }),
         Token::TimeSignature(ratio) => Some(PreambleNode::TimeSignature{ literal, ratio }),
+        Token::Part(name, track_id) => Some(PreambleNode::Part{ literal, name, track_id }),
+        Token::Voice(voice) => Some(PreambleNode::Voice{ literal, voice }),
         Token::Endline() => Some(PreambleNode::Endline(literal)),
-        Token::Track(_) |
-        Token::ScaleDegree(_) |
+        Token::Track(..) |
+        Token::ScaleDegree(_, _) |
         Token::AbsolutePitch(_) |
         Token::MeasureBar(_, _) |
+        Token::EndRepeat(_) |
         Token::Section(_) |
+        Token::ChordOpen |
+        Token::ChordClose |
+        Token::TempoRamp(_) |
+        Token::Hairpin(_) |
         Token::Note(_) => None
     }
 }
@@ -293,22 +392,60 @@ fn eat_preamble_atomic(parser: &mut Parser) -> CompileResult<PreambleNode>
     Err(CompileError::GenericSyntax("Expected a preamble token, but nothing left".to_string()))
 }
 
-fn eat_measure_block(parser: &mut Parser) -> CompileResult<Option<MeasureNode>>
+// a brace-delimited list of pitches, all of which are sung by the notes
+// that follow it
+fn eat_chord(parser: &mut Parser) -> CompileResult<StaffNode>
+{
+    let (open, _) = parser.take().ok_or(CompileError::GenericSyntax(
+        "Expected a chord, but nothing left".to_string()))?;
+
+    let mut pitches = vec![];
+    while let Some((_, token)) = parser.peek()
+    {
+        match token
+        {
+            Token::ChordClose =>
+            {
+                parser.take();
+                if pitches.is_empty()
+                {
+                    return Err(CompileError::EmptyChord(open));
+                }
+                return Ok(StaffNode::Chord { literal: open, pitches });
+            },
+            Token::ScaleDegree(_, _) |
+            Token::AbsolutePitch(_) =>
+            {
+                pitches.push(eat_staff_atomic(parser)?);
+            },
+            _ => break,
+        }
+    }
+
+    Err(CompileError::UnclosedChord(open))
+}
+
+fn eat_measure_block(parser: &mut Parser) -> Option<MeasureNode>
 {
     let mut staff = vec![];
     let mut skip_next_bar = true;
+    // an error was already reported for this measure
+    let mut recovered = false;
+    // a chord left open, whose late close isn't a second mistake
+    let mut unclosed_chord = false;
 
     let mut measure_start: Option<(Literal, Token)> = None;
     let mut measure_end: Option<(Literal, Token)> = None;
 
-    while let Some((literal, token)) = parser.peek()
+    while let Some((literal, token)) = parser.peek_copy()
     {
         measure_start.get_or_insert((literal.clone(), token.clone()));
         measure_end = Some((literal.clone(), token.clone()));
 
         let node = match token
         {
-            Token::MeasureBar(_, _) =>
+            Token::MeasureBar(_, _) |
+            Token::EndRepeat(_) =>
             {
                 if skip_next_bar
                 {
@@ -326,25 +463,67 @@ fn eat_measure_block(parser: &mut Parser) -> CompileResult<Option<MeasureNode>>
                 break
             }
             Token::AbsolutePitch(_) |
-            Token::ScaleDegree(_) |
+            Token::ScaleDegree(_, _) |
             Token::Endline() |
-            Token::Track(_) |
+            Token::Track(..) |
+            Token::Tempo(_) |
+            Token::TempoRamp(_) |
+            Token::Dynamic(_) |
+            Token::Hairpin(_) |
             Token::Note(_) =>
             {
                 skip_next_bar = false;
                 Some(eat_staff_atomic(parser))
             },
-            Token::Dynamic(_) |
-            Token::Tempo(_) |
+            Token::ChordOpen =>
+            {
+                skip_next_bar = false;
+                Some(eat_chord(parser))
+            },
+            Token::ChordClose if unclosed_chord =>
+            {
+                parser.take();
+                unclosed_chord = false;
+                None
+            },
+            Token::ChordClose =>
+            {
+                parser.take();
+                Some(Err(CompileError::Unexpected(
+                    "Chord closed but never opened".to_string(), token, literal)))
+            },
+            // a track marker may be followed by that track's own meter
+            // and voice
+            Token::TimeSignature(_) |
+            Token::Voice(_) if matches!(staff.last(),
+                Some(StaffNode::Track { .. } | StaffNode::TimeSignature { .. } | StaffNode::Voice { .. })) =>
+            {
+                Some(eat_staff_atomic(parser))
+            },
             Token::TimeSignature(_) |
-            Token::Scale(_) => Some(Err(CompileError::Unexpected(
-                "Illegal token in measure block".to_string(),
-                token.clone(), literal.clone()))),
+            Token::Voice(_) |
+            Token::Scale(_) |
+            Token::Part(..) =>
+            {
+                parser.report(CompileError::Unexpected(
+                    "Illegal token in measure block".to_string(), token, literal));
+                parser.take();
+                parser.synchronize();
+                recovered = true;
+                None
+            },
         };
 
-        if let Some(n) = node
+        match node
         {
-            staff.push(n?);
+            Some(Ok(n)) => staff.push(n),
+            Some(Err(e)) =>
+            {
+                unclosed_chord = matches!(e, CompileError::UnclosedChord(_));
+                parser.report(e);
+                recovered = true;
+            },
+            None => (),
         }
     }
 
@@ -357,19 +536,22 @@ fn eat_measure_block(parser: &mut Parser) -> CompileResult<Option<MeasureNode>>
         }
     });
 
-    let start = measure_start.ok_or(
-        CompileError::GenericSyntax("No start token for measure".to_string()))?;
-    let end = measure_end.ok_or(
-        CompileError::GenericSyntax("No end token for measure".to_string()))?;
+    let start = measure_start?;
+    let end = measure_end?;
 
+    // a measure emptied by an error isn't a second mistake
     if staff.is_empty()
     {
-        return Err(CompileError::EmptyMeasure(start.0, end.0))
+        if !recovered
+        {
+            parser.report(CompileError::EmptyMeasure(start.0, end.0));
+        }
+        return None
     }
 
     if contains_endline && staff.len() == 1
     {
-        return Ok(None)
+        return None
     }
 
     staff = staff.into_iter().filter(|node|
@@ -381,7 +563,7 @@ fn eat_measure_block(parser: &mut Parser) -> CompileResult<Option<MeasureNode>>
         }
     }).collect();
 
-    Ok(Some(MeasureNode { start, end, staff }))
+    Some(MeasureNode { start, end, staff })
 }
 
 fn staff_node_to_string(node: &StaffNode, level: u32) -> String
@@ -394,7 +576,21 @@ fn staff_node_to_string(node: &StaffNode, level: u32) -> String
         StaffNode::Note{literal, ..} => format!("{}[note] {}", pad, literal.literal),
         StaffNode::Track{literal, ..} => format!("{}[track] {}", pad, literal.literal),
         StaffNode::ScaleDegree{literal, ..}  => format!("{}[relpitch] {}", pad, literal.literal),
+        StaffNode::Chord{pitches, ..} => format!("{}[chord] {{{}}}", pad, pitches.iter().map(|p|
+        {
+            match p
+            {
+                StaffNode::AbsolutePitch{literal, ..} |
+                StaffNode::ScaleDegree{literal, ..} => literal.literal.clone(),
+                _ => "?".to_string(),
+            }
+        })
+        .collect::<Vec<_>>().join(" ")),
         StaffNode::MeasureBar{literal, ..}  => format!("{}[mb] {}", pad, literal.literal),
+        StaffNode::Tempo{literal, ..} => format!("{}[tempo] {}", pad, literal.literal),
+        StaffNode::Dynamic{literal, ..} => format!("{}[dyn] {}", pad, literal.literal),
+        StaffNode::TimeSignature{literal, ..} => format!("{}[time] {}", pad, literal.literal),
+        StaffNode::Voice{literal, ..} => format!("{}[voice] {}", pad, literal.literal),
         StaffNode::Endline { .. } => format!("{}[endline]", pad),
     }
 }
@@ -409,6 +605,8 @@ fn preamble_node_to_string(node: &PreambleNode, level: u32) -> String
         PreambleNode::DynamicLevel { literal, .. } => format!("{}[dyn] {}", pad, literal.literal),
         PreambleNode::TimeSignature { literal, .. } => format!("{}[time] {}", pad, literal.literal),
         PreambleNode::Scale { literal, .. } => format!("{}[scale] {}", pad, literal.literal),
+        PreambleNode::Part { literal, .. } => format!("{}[part] {}", pad, literal.literal),
+        PreambleNode::Voice { literal, .. } => format!("{}[voice] {}", pad, literal.literal),
         PreambleNode::Endline(literal) => format!("{}[endline]", pad),
     }
 }
@@ -469,82 +667,490 @@ fn pluralize<T: std::cmp::PartialEq<usize>>(count: T) -> &'static str
 }
 
 pub fn print_error(error: &CompileError)
+{
+    print_diagnostic(error);
+
+    let count = error.count();
+    println!("{}\n", format!("Compilation failed with {} error{}.",
+        count, pluralize(count)).bold());
+}
+
+// an underlined span of source in a diagnostic; the primary label is
+// drawn with carets, secondary labels with dashes
+struct Label<'a>
+{
+    start: &'a Literal,
+    end: &'a Literal,
+    primary: bool,
+    message: String
+}
+
+impl<'a> Label<'a>
+{
+    fn primary(start: &'a Literal, end: &'a Literal, message: &str) -> Self
+    {
+        Label { start, end, primary: true, message: message.to_string() }
+    }
+
+    fn secondary(literal: &'a Literal, message: &str) -> Self
+    {
+        Label { start: literal, end: literal, primary: false, message: message.to_string() }
+    }
+}
+
+// e.g. "eighth notes" for a denominator of 8
+fn note_value_name(denominator: u8, singular: bool) -> String
+{
+    let name = match denominator
+    {
+        1  => "whole note".to_string(),
+        2  => "half note".to_string(),
+        4  => "quarter note".to_string(),
+        8  => "eighth note".to_string(),
+        16 => "sixteenth note".to_string(),
+        32 => "thirty-second note".to_string(),
+        d  => format!("1/{} note", d),
+    };
+    if singular { name } else { name + "s" }
+}
+
+const TAB_WIDTH: usize = 4;
+
+// how far into the echoed line a byte offset of the source lands, counting
+// characters rather than bytes and expanding tabs
+fn display_column(source: &str, byte: usize) -> usize
+{
+    let text = source.get(..byte.min(source.len())).unwrap_or(source);
+    text.chars().fold(0, |col, c| if c == '\t' { (col / TAB_WIDTH + 1) * TAB_WIDTH } else { col + 1 })
+}
+
+fn expand_tabs(source: &str) -> String
+{
+    let mut out = String::new();
+    for c in source.chars()
+    {
+        if c == '\t'
+        {
+            out.push_str(&" ".repeat(TAB_WIDTH - out.chars().count() % TAB_WIDTH));
+        }
+        else
+        {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// a literal's 1-based column in characters, as editors count them
+fn char_column(literal: &Literal) -> usize
+{
+    let byte = literal.colno.saturating_sub(1).min(literal.source.len());
+    literal.source.get(..byte).map_or(literal.colno, |s| s.chars().count() + 1)
+}
+
+fn render_label(label: &Label, gutter: usize) -> Vec<String>
+{
+    let source = &label.start.source;
+    let first = display_column(source, label.start.colno.saturating_sub(1));
+    let last = if label.end.lineno == label.start.lineno && label.end.colno >= label.start.colno
+    {
+        let end = label.end.colno.saturating_sub(1);
+        // synthetic literals like <eol> don't occupy any source text
+        if label.end.literal.starts_with('<') { display_column(source, end) + 1 }
+        else { display_column(source, end + label.end.literal.len()) }
+    }
+    else
+    {
+        // spans running onto later lines are underlined to the end of the first
+        display_column(source, source.len()) + 1
+    };
+
+    let marker = if label.primary { "^" } else { "-" };
+    let underline = marker.repeat(last.saturating_sub(first).max(1));
+    let underline = if label.primary { underline.red().bold() } else { underline.blue().bold() };
+    let pad = " ".repeat(gutter);
+    let bar = "|".blue().bold();
+
+    vec![
+        format!("{} {}", pad, bar),
+        format!("{:>width$} {} {}", label.start.lineno.to_string().blue().bold(), bar,
+            expand_tabs(source), width = gutter),
+        format!("{} {} {}{} {}", pad, bar, " ".repeat(first), underline, label.message),
+    ]
+}
+
+fn render_diagnostic(message: &str, labels: &[Label], notes: &[String]) -> String
+{
+    let gutter = labels.iter().map(|l| l.start.lineno.to_string().len()).max().unwrap_or(1);
+    let pad = " ".repeat(gutter);
+
+    let mut lines = vec![format!("{}: {}", "error".red().bold(), message.bold())];
+
+    if let Some(primary) = labels.iter().find(|l| l.primary)
+    {
+        let filename = if primary.start.filename.is_empty() { "<source>" } else { &primary.start.filename };
+        lines.push(format!("{}{} {}:{}:{}", pad, "-->".blue().bold(),
+            filename, primary.start.lineno, char_column(primary.start)));
+    }
+
+    for label in labels
+    {
+        lines.extend(render_label(label, gutter));
+    }
+
+    for note in notes
+    {
+        lines.push(format!("{} {} note: {}", pad, "=".blue().bold(), note));
+    }
+
+    lines.join("\n")
+}
+
+fn print_diagnostic(error: &CompileError)
+{
+    if let CompileError::Diagnostics(errors) = error
+    {
+        for e in errors
+        {
+            print_diagnostic(e);
+        }
+        return;
+    }
+
+    let (message, labels, notes) = describe_error(error);
+    println!("{}\n", render_diagnostic(&message, &labels, &notes));
+}
+
+// the headline, source labels and trailing notes for a single error
+fn describe_error(error: &CompileError) -> (String, Vec<Label>, Vec<String>)
 {
     match error
     {
+        CompileError::Diagnostics(errors) =>
+        (
+            format!("{} errors", errors.len()), vec![], vec![]
+        ),
         CompileError::InvalidSyntax(literal) =>
-        {
-            println!("\n    {}\n\n    \"{}\", line {}, col {}\n",
-                "Invalid syntax.".bold(),
-                literal.literal, literal.lineno, literal.colno);
-        },
+        (
+            "invalid syntax".to_string(),
+            vec![Label::primary(literal, literal, "not a valid token")],
+            vec![]
+        ),
         CompileError::Generic(msg) |
         CompileError::GenericSyntax(msg) =>
-        {
-            println!("\n  Generic error: {}\n", msg);
-        },
+        (
+            msg.clone(), vec![], vec![]
+        ),
         CompileError::Unexpected(msg, token, literal) =>
-        {
-            println!("\n  Unexpected token: {}\n    Problematic token -- \"{}\", line {}, col {}\n",
-                msg, literal.literal, literal.lineno, literal.colno);
-        },
+        (
+            format!("unexpected token: {}", msg),
+            vec![Label::primary(literal, literal, "unexpected here")],
+            vec![]
+        ),
         CompileError::PreambleOrder(section, first, cur) =>
-        {
-            println!("\n  Cannot declare preamble element after staff has begun.");
-            println!("    In this section --          \"{}\", line {}, col {}",
-                section.literal, section.lineno, section.colno);
-            println!("    Staff begins here --        \"{}\", line {}, col {}",
-                first.literal, first.lineno, first.colno);
-            println!("    Problematic element is --   \"{}\", line {}, col {}\n",
-                cur.literal, cur.lineno, cur.colno);
-        },
+        (
+            "cannot declare preamble element after staff has begun".to_string(),
+            vec![
+                Label::primary(cur, cur, "preamble element"),
+                Label::secondary(first, "staff begins here"),
+                Label::secondary(section, "in this section"),
+            ],
+            vec![]
+        ),
         CompileError::EmptyMeasure(start, end) =>
-        {
-            println!("\n  Empty measure.");
-            println!("    Measure starts here -- \"{}\", line {}, col {}",
-                start.literal, start.lineno, start.colno);
-            println!("    Measure ends here -- \"{}\", line {}, col {}\n",
-                end.literal, end.lineno, end.colno);
-        },
+        (
+            "empty measure".to_string(),
+            vec![Label::primary(start, end, "this measure has no notes")],
+            vec![]
+        ),
         CompileError::TimeSignatureViolation{ measure, time_signature, nominal } =>
         {
-            println!("\n    {}\n", "Time signature violation.".bold());
-            println!("    This measure is {} beats, which violates time signature {:?}",
-                measure.count_beats(), nominal);
-            println!("    Time signature declared here -- {}", time_signature.to_string());
-            println!("    Initiating element --  {}", measure.start.to_string());
-            println!("    Terminating element -- {}\n", measure.end.to_string());
-
-            // show_file(&measure.start, &measure.end, filename);
+            let beats = measure.count_beats();
+            let mut expected = format!("each measure must be exactly {} {}",
+                nominal.0, note_value_name(nominal.1, nominal.0 == 1));
+            if pulse_units(nominal) == 3
+            {
+                expected += &format!(" ({} dotted {})", nominal.0 / 3,
+                    note_value_name(nominal.1 / 2, nominal.0 == 3));
+            }
+            (
+                "time signature violation".to_string(),
+                vec![
+                    Label::primary(&measure.start, &measure.end, &format!("this measure is {} {}",
+                        beats, note_value_name(nominal.1, beats == Fraction::from(1)))),
+                    Label::secondary(time_signature,
+                        &format!("time signature {}/{} declared here", nominal.0, nominal.1)),
+                ],
+                vec![expected]
+            )
         },
         CompileError::NetworkError(e) =>
-        {
-            println!("\n    {}\n", "Network error.".bold());
-            println!("    {:?}\n", e);
-        },
+        (
+            "network error".to_string(), vec![], vec![format!("{:?}", e)]
+        ),
+        CompileError::EngineError(msg) =>
+        (
+            "TTS engine error".to_string(), vec![], vec![msg.clone()]
+        ),
         CompileError::FileError(e) =>
-        {
-            println!("\n    {}\n", "File IO error.".bold());
-            println!("    {:?}\n", e);
-        }
+        (
+            "file IO error".to_string(), vec![], vec![format!("{:?}", e)]
+        ),
         CompileError::TrackTooLarge =>
-        {
-            println!("\n    {}\n\n", "Track too large; API call failed.".bold());
-        }
+        (
+            "track too large; API call failed".to_string(), vec![],
+            vec!["the track was split as far as measures and rests allow".to_string()]
+        ),
         CompileError::DifferingMeasureCounts(ta, asize, tb, bsize) =>
-        {
-            println!("\n    {}\n", "Tracks have inconsistent length.".bold());
-            println!("    Track {} has {} measure{};", ta, asize, pluralize(*asize));
-            println!("    Track {} has {} measure{}.\n", tb, bsize, pluralize(*bsize));
-        }
+        (
+            "tracks have inconsistent length".to_string(),
+            vec![],
+            vec![
+                format!("track {} has {} measure{}", ta, asize, pluralize(*asize)),
+                format!("track {} has {} measure{}", tb, bsize, pluralize(*bsize)),
+            ]
+        ),
+        CompileError::DifferingTrackDurations(ta, adur, tb, bdur) =>
+        (
+            "tracks have inconsistent length".to_string(),
+            vec![],
+            vec![
+                format!("track {} lasts {} {}", ta, adur, note_value_name(4, *adur == Fraction::from(1))),
+                format!("track {} lasts {} {}", tb, bdur, note_value_name(4, *bdur == Fraction::from(1))),
+            ]
+        ),
         CompileError::EmptyTrack(idx) =>
-        {
-            println!("\n    {}\n\n",
-                format!("Track {} contains no measures.", idx).bold());
-        }
+        (
+            format!("track {} contains no measures", idx), vec![], vec![]
+        ),
+        CompileError::NestedRepeat { outer, inner } =>
+        (
+            "repeats cannot be nested".to_string(),
+            vec![
+                Label::primary(inner, inner, "inner repeat opened here"),
+                Label::secondary(outer, "outer repeat opened here"),
+            ],
+            vec![]
+        ),
+        CompileError::UnclosedRepeat(literal) =>
+        (
+            "repeat is never closed".to_string(),
+            vec![Label::primary(literal, literal, "repeat opened here")],
+            vec![]
+        ),
+        CompileError::UnopenedRepeat(literal) =>
+        (
+            "repeat is closed but was never opened".to_string(),
+            vec![Label::primary(literal, literal, "repeat closed here")],
+            vec![]
+        ),
+        CompileError::InvalidRepeatCount(literal) =>
+        (
+            "repeat count must be at least 1".to_string(),
+            vec![Label::primary(literal, literal, "repeat closed here")],
+            vec![]
+        ),
+        CompileError::UnterminatedTie(literal) =>
+        (
+            "tie has no note to continue into".to_string(),
+            vec![Label::primary(literal, literal, "tied here")],
+            vec!["ties cannot cross into another section".to_string()]
+        ),
+        CompileError::MismatchedTie { tie, next } =>
+        (
+            "tied notes must continue on the same pitch and syllable".to_string(),
+            vec![
+                Label::primary(tie, tie, "tied here"),
+                Label::secondary(next, "but the next note differs"),
+            ],
+            vec![]
+        ),
+        CompileError::EmptyChord(literal) =>
+        (
+            "chord has no pitches".to_string(),
+            vec![Label::primary(literal, literal, "chord opened here")],
+            vec![]
+        ),
+        CompileError::UnclosedChord(literal) =>
+        (
+            "chord is never closed".to_string(),
+            vec![Label::primary(literal, literal, "chord opened here")],
+            vec!["a chord may only contain pitches and scale degrees".to_string()]
+        ),
+        CompileError::UnterminatedTempoRamp(literal) =>
+        (
+            "tempo change has no target tempo".to_string(),
+            vec![Label::primary(literal, literal, "tempo change starts here")],
+            vec!["end a RIT or ACCEL with the tempo it arrives at, e.g. 60BPM".to_string()]
+        ),
+        CompileError::ZeroTempo(literal) =>
+        (
+            "tempo must be above zero".to_string(),
+            vec![Label::primary(literal, literal, "tempo set here")],
+            vec!["the slowest tempo is 1BPM".to_string()]
+        ),
+        CompileError::MismatchedTempoRamp { ramp, target } =>
+        (
+            format!("{} must arrive at a {} tempo", ramp.literal,
+                if ramp.literal == "RIT" { "slower" } else { "faster" }),
+            vec![
+                Label::primary(target, target, "target tempo"),
+                Label::secondary(ramp, "tempo change starts here"),
+            ],
+            vec![]
+        ),
+        CompileError::ConflictingTempoMarks { first, second } =>
+        (
+            "conflicting tempo marks".to_string(),
+            vec![
+                Label::primary(second, second, "this tempo mark"),
+                Label::secondary(first, "falls on the same beat as this one"),
+            ],
+            vec![]
+        ),
+        CompileError::ConflictingTimeSignatures { first, second } =>
+        (
+            "conflicting time signatures for one track".to_string(),
+            vec![
+                Label::primary(second, second, "this time signature"),
+                Label::secondary(first, "differs from the one given here"),
+            ],
+            vec!["a track keeps one time signature for the whole section".to_string()]
+        ),
+        CompileError::InvalidTrackHeader(literal) =>
+        (
+            "invalid track header".to_string(),
+            vec![Label::primary(literal, literal, "not a track header")],
+            vec![
+                "track headers look like [1 gain=-3dB pan=0.3], each attribute at most once".to_string(),
+                "pan runs from -1 (hard left) to 1 (hard right)".to_string(),
+            ]
+        ),
+        CompileError::UnknownPart(literal) =>
+        (
+            "no such part".to_string(),
+            vec![Label::primary(literal, literal, "this part was never declared")],
+            vec!["declare it ahead of the staff, e.g. PART soprano = [1]".to_string()]
+        ),
+        CompileError::ConflictingParts { first, second } =>
+        (
+            "conflicting part declarations".to_string(),
+            vec![
+                Label::primary(second, second, "this declaration"),
+                Label::secondary(first, "differs from the one given here"),
+            ],
+            vec!["each part names exactly one track, and each track has at most one part".to_string()]
+        ),
+        CompileError::UnknownVoice(literal) =>
+        (
+            "unknown voice".to_string(),
+            vec![Label::primary(literal, literal, "not one of the engine's speakers")],
+            vec!["voices are paul, harry, frank, dennis, betty, ursula, wendy, rita and kit".to_string()]
+        ),
+        CompileError::ConflictingVoices { first, second } =>
+        (
+            "conflicting voices for one track".to_string(),
+            vec![
+                Label::primary(second, second, "this voice"),
+                Label::secondary(first, "differs from the one given here"),
+            ],
+            vec!["a track keeps one voice for the whole section".to_string()]
+        ),
+        CompileError::ConflictingTrackAttributes { first, second } =>
+        (
+            "conflicting attributes for one track".to_string(),
+            vec![
+                Label::primary(second, second, "this header"),
+                Label::secondary(first, "differs from the one given here"),
+            ],
+            vec!["within a section, a track's header may only repeat what it already said".to_string()]
+        ),
+        CompileError::UnterminatedHairpin(literal) =>
+        (
+            "hairpin has no target dynamic".to_string(),
+            vec![Label::primary(literal, literal, "hairpin starts here")],
+            vec!["end a CRESC or DIM with the dynamic it arrives at, e.g. FORTE".to_string()]
+        ),
+        CompileError::MismatchedHairpin { hairpin, target } =>
+        (
+            format!("{} must arrive at a {} dynamic", hairpin.literal,
+                if hairpin.literal == "CRESC" { "louder" } else { "softer" }),
+            vec![
+                Label::primary(target, target, "target dynamic"),
+                Label::secondary(hairpin, "hairpin starts here"),
+            ],
+            vec![]
+        ),
+        CompileError::PitchOutOfRange(literal) =>
+        (
+            "pitch is out of range".to_string(),
+            vec![Label::primary(literal, literal, "the engine can't sing this pitch")],
+            vec!["the engine sings from C2 up to C5".to_string()]
+        ),
     }
 }
 
+#[test]
+fn diagnostic_snippets()
+{
+    colored::control::set_override(false);
+
+    let error = crate::semantics::semantics_of("4/4\n[1] | . . . | . . . . |").unwrap_err();
+
+    let (measure, time_signature) = match &error
+    {
+        CompileError::TimeSignatureViolation { measure, time_signature, .. } => (measure, time_signature),
+        other => panic!("expected a time signature violation, got {:?}", other),
+    };
+
+    let rendered = render_diagnostic("time signature violation", &[
+        Label::primary(&measure.start, &measure.end, "this measure is 3 beats"),
+        Label::secondary(time_signature, "declared here"),
+    ], &[]);
+
+    assert_eq!(rendered, indoc! {"
+        error: time signature violation
+         --> <source>:2:5
+          |
+        2 | [1] | . . . | . . . . |
+          |     ^^^^^^^^^ this measure is 3 beats
+          |
+        1 | 4/4
+          | --- declared here"});
+
+    // columns count characters, and tabs line up with the echoed source
+    let error = match lex_multiline_string("[1]\t| ä |\t| ö:3 |")
+    {
+        Err(CompileError::Diagnostics(mut errors)) => errors.remove(1),
+        other => panic!("expected two diagnostics, got {:?}", other),
+    };
+    let (message, labels, notes) = describe_error(&error);
+    assert_eq!(render_diagnostic(&message, &labels, &notes), indoc! {"
+        error: invalid syntax
+         --> <source>:1:13
+          |
+        1 | [1] | ä |   | ö:3 |
+          |               ^^^ not a valid token"});
+}
+
+#[test]
+fn time_signature_messages()
+{
+    let message = |source: &str| -> (String, Vec<String>)
+    {
+        let error = crate::semantics::semantics_of(source).unwrap_err();
+        let (_, labels, notes) = describe_error(&error);
+        (labels[0].message.clone(), notes)
+    };
+
+    assert_eq!(message("4/4 [1] | . . . |"), ("this measure is 3 quarter notes".to_string(),
+        vec!["each measure must be exactly 4 quarter notes".to_string()]));
+    assert_eq!(message("6/8 [1] | . . . . ./2 |"), ("this measure is 9/2 eighth notes".to_string(),
+        vec!["each measure must be exactly 6 eighth notes (2 dotted quarter notes)".to_string()]));
+    assert_eq!(message("3/2 [1] | . |"), ("this measure is 1 half note".to_string(),
+        vec!["each measure must be exactly 3 half notes".to_string()]));
+}
+
 fn assert_ast_results(source: &str, ast_repr: &str)
 {
     let tokens = lex_multiline_string(source).unwrap();
@@ -599,6 +1205,145 @@ fn parsing_test()
         [end]"});
 }
 
+#[test]
+fn repeat_parsing()
+{
+    assert_ast_results("|: . | ./2 ./2 :|x3 . |",
+        indoc! {"
+        [top]
+            [section] <implicit-section>
+                [preamble]
+                [staff]
+                    [measure] |: .. |
+                        [note] .
+                    [measure] | .. :|x3
+                        [note] ./2
+                        [note] ./2
+                    [measure] :|x3 .. |
+                        [note] .
+        [end]"});
+}
+
+#[test]
+fn inline_mark_parsing()
+{
+    assert_ast_results("100BPM | . . RIT . 60BPM . |",
+        indoc! {"
+        [top]
+            [section] <implicit-section>
+                [preamble]
+                    [tempo] 100BPM
+                [staff]
+                    [measure] | .. |
+                        [note] .
+                        [note] .
+                        [tempo] RIT
+                        [note] .
+                        [tempo] 60BPM
+                        [note] .
+        [end]"});
+
+    assert_ast_results("PIANO | . CRESC . . FORTE |",
+        indoc! {"
+        [top]
+            [section] <implicit-section>
+                [preamble]
+                    [dyn] PIANO
+                [staff]
+                    [measure] | .. |
+                        [note] .
+                        [dyn] CRESC
+                        [note] .
+                        [note] .
+                        [dyn] FORTE
+        [end]"});
+}
+
+#[test]
+fn track_time_signature_parsing()
+{
+    assert_ast_results("4/4\n[1] | . . . . |\n[2] 3/4 | . . . |",
+        indoc! {"
+        [top]
+            [section] <implicit-section>
+                [preamble]
+                    [time] 4/4
+                [staff]
+                    [measure] [1] .. |
+                        [track] [1]
+                    [measure] | .. |
+                        [note] .
+                        [note] .
+                        [note] .
+                        [note] .
+                    [measure] | .. |
+                        [track] [2]
+                        [time] 3/4
+                    [measure] | .. |
+                        [note] .
+                        [note] .
+                        [note] .
+        [end]"});
+}
+
+#[test]
+fn chord_parsing()
+{
+    assert_ast_results("| {1 3 5} ah:2 {C2 E2} ah:2 |",
+        indoc! {"
+        [top]
+            [section] <implicit-section>
+                [preamble]
+                [staff]
+                    [measure] | .. |
+                        [chord] {1 3 5}
+                        [note] ah:2
+                        [chord] {C2 E2}
+                        [note] ah:2
+        [end]"});
+
+    // the unclosed chord's late close isn't reported again, but a stray
+    // one is
+    let tokens = lex_multiline_string("| {} ah:2 {1 3 ah:2 } | ah } |").unwrap();
+    match parse_to_ast(&tokens)
+    {
+        Err(CompileError::Diagnostics(errors)) =>
+        {
+            assert_eq!(errors.len(), 3);
+            assert!(matches!(&errors[0], CompileError::EmptyChord(_)));
+            assert!(matches!(&errors[1], CompileError::UnclosedChord(_)));
+            assert!(matches!(&errors[2], CompileError::Unexpected(_, Token::ChordClose, l) if l.colno > 24));
+        },
+        other => panic!("expected three diagnostics, got {:?}", other),
+    }
+}
+
+#[test]
+fn parser_recovery()
+{
+    let tokens = lex_multiline_string(indoc! {"
+        4/4
+        [1] | . . 4/4 . . | . . . . |
+        [2] | . CMAJOR . . . | | . . . . |
+    "}).unwrap();
+
+    match parse_to_ast(&tokens)
+    {
+        Err(CompileError::Diagnostics(errors)) =>
+        {
+            assert_eq!(errors.len(), 3);
+            assert!(matches!(&errors[0], CompileError::Unexpected(_, Token::TimeSignature(_), _)));
+            assert!(matches!(&errors[1], CompileError::Unexpected(_, Token::Scale(_), _)));
+            assert!(matches!(&errors[2], CompileError::EmptyMeasure(_, _)));
+        },
+        other => panic!("expected three diagnostics, got {:?}", other),
+    }
+
+    // one mistake, one error
+    let tokens = lex_multiline_string("[1] | CMAJOR | . . . . |").unwrap();
+    assert!(matches!(parse_to_ast(&tokens), Err(CompileError::Unexpected(_, Token::Scale(_), _))));
+}
+
 fn test_parse_file(filename: &str)
 {
     let path = Path::new(filename);
@@ -630,3 +1375,20 @@ fn parse_the_sound_of_music()
 {
     test_parse_file("examples/soundofmusic.md");
 }
+
+#[test]
+fn part_parsing()
+{
+    assert_ast_results("PART soprano = [1]\n[soprano] | ah |",
+        indoc! {"
+        [top]
+            [section] <implicit-section>
+                [preamble]
+                    [part] PART soprano = [1]
+                [staff]
+                    [measure] [soprano] .. |
+                        [track] [soprano]
+                    [measure] | .. |
+                        [note] ah
+        [end]"});
+}
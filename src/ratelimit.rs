@@ -0,0 +1,155 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// A token bucket shared by every thread talking to one service. Each
+// request takes a token; tokens refill continuously at `rate` per second
+// up to `capacity`, so short bursts go through and sustained load is
+// spread out.
+pub struct TokenBucket
+{
+    capacity: f64,
+    rate: f64,
+    state: Mutex<(f64, Instant)>
+}
+
+impl TokenBucket
+{
+    pub fn new(capacity: u32, rate: f64) -> Self
+    {
+        TokenBucket
+        {
+            capacity: capacity.max(1) as f64,
+            rate,
+            state: Mutex::new((capacity.max(1) as f64, Instant::now()))
+        }
+    }
+
+    // takes a token if one is available, or returns how long to wait
+    // before the next one will be
+    pub fn try_acquire(&self) -> Result<(), Duration>
+    {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> Result<(), Duration>
+    {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = *state;
+        let elapsed = now.saturating_duration_since(last).as_secs_f64();
+        let tokens = (tokens + elapsed * self.rate).min(self.capacity);
+
+        if tokens >= 1.0
+        {
+            *state = (tokens - 1.0, now);
+            return Ok(());
+        }
+
+        *state = (tokens, now);
+        if self.rate <= 0.0
+        {
+            return Err(Duration::from_secs(1));
+        }
+        Err(Duration::from_secs_f64((1.0 - tokens) / self.rate))
+    }
+
+    // blocks the calling thread until a token is available
+    pub fn acquire(&self)
+    {
+        while let Err(wait) = self.try_acquire()
+        {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+// Exponential backoff with "full jitter": the nth retry waits a random
+// time between zero and base * 2^n, capped at max. Randomizing the whole
+// delay keeps workers that were throttled together from retrying in
+// lockstep.
+pub struct Backoff
+{
+    pub base: Duration,
+    pub max: Duration,
+    attempt: u32
+}
+
+impl Backoff
+{
+    pub fn new(base: Duration, max: Duration) -> Self
+    {
+        Backoff { base, max, attempt: 0 }
+    }
+
+    // the upper bound on the next delay, before jitter
+    pub fn ceiling(&self) -> Duration
+    {
+        let factor = 1u32.checked_shl(self.attempt).unwrap_or(u32::MAX);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+
+    pub fn next_delay(&mut self) -> Duration
+    {
+        let ceiling = self.ceiling();
+        self.attempt = self.attempt.saturating_add(1);
+        ceiling.mul_f64(jitter())
+    }
+}
+
+// a uniform value in [0, 1); good enough for spreading out retries, not
+// for anything that needs real randomness
+fn jitter() -> f64
+{
+    static STATE: AtomicU64 = AtomicU64::new(0);
+
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64).unwrap_or(0);
+    let mut x = STATE.fetch_add(0x9E3779B97F4A7C15, Ordering::Relaxed) ^ seed;
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xFF51AFD7ED558CCD);
+    x ^= x >> 33;
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[test]
+fn token_bucket_refill()
+{
+    let bucket = TokenBucket::new(2, 4.0);
+    let start = Instant::now();
+
+    // the initial burst is the bucket's capacity
+    assert!(bucket.try_acquire_at(start).is_ok());
+    assert!(bucket.try_acquire_at(start).is_ok());
+    let wait = bucket.try_acquire_at(start).unwrap_err();
+    assert_eq!(wait, Duration::from_millis(250));
+
+    // a token comes back every quarter second, never more than capacity
+    assert!(bucket.try_acquire_at(start + Duration::from_millis(250)).is_ok());
+    assert!(bucket.try_acquire_at(start + Duration::from_millis(250)).is_err());
+    let later = start + Duration::from_secs(60);
+    assert!(bucket.try_acquire_at(later).is_ok());
+    assert!(bucket.try_acquire_at(later).is_ok());
+    assert!(bucket.try_acquire_at(later).is_err());
+}
+
+#[test]
+fn backoff_growth()
+{
+    let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(8));
+    let ceilings : Vec<Duration> = (0..7).map(|_|
+    {
+        let ceiling = backoff.ceiling();
+        assert!(backoff.next_delay() <= ceiling);
+        ceiling
+    })
+    .collect();
+
+    assert_eq!(ceilings, [500, 1000, 2000, 4000, 8000, 8000, 8000]
+        .map(Duration::from_millis));
+
+    for _ in 0..100
+    {
+        let j = jitter();
+        assert!((0.0..1.0).contains(&j));
+    }
+}
@@ -11,14 +11,44 @@ pub struct Section
     pub id: u32,
     pub name: String,
     pub tempo: u16,
+    pub tempo_map: TempoMap,
     pub dynamic: DynamicLevel,
+    // per track, starting from the section's dynamic
+    pub dynamics: HashMap<u32, DynamicMap>,
     pub scale: Scale,
     pub time_signature: Option<(Literal, TimeSignature)>,
+    // tracks written in a meter of their own, e.g. "[2] 3/4"; their notes
+    // are still measured in the section's beat units
+    pub track_time_signatures: HashMap<u32, (Literal, TimeSignature)>,
+    // mixer settings for every track in the section, as given by its most
+    // recent header in this or an earlier section
+    pub track_attributes: HashMap<u32, TrackAttributes>,
+    // names of the parts declared so far, by track
+    pub parts: HashMap<u32, String>,
+    // the speaker each track is sung by, where one was chosen
+    pub voices: HashMap<u32, Voice>,
     pub tracks: TrackMap
 }
 
 impl Section
 {
+    // the length of one of this section's beats, in quarter notes
+    pub fn unit_quarters(&self) -> Fraction
+    {
+        self.time_signature.as_ref().map(|(_, ts)| unit_quarters(ts)).unwrap_or(Fraction::from(1))
+    }
+
+    pub fn pulse_units(&self) -> u8
+    {
+        self.time_signature.as_ref().map(|(_, ts)| pulse_units(ts)).unwrap_or(1)
+    }
+
+    // how diagnostics and output files refer to a track
+    pub fn track_label(&self, track_id: u32) -> String
+    {
+        self.parts.get(&track_id).cloned().unwrap_or(track_id.to_string())
+    }
+
     pub fn to_string(&self) -> String
     {
         let mut sections = vec![
@@ -30,7 +60,7 @@ impl Section
             for measure in measures
             {
                 let s = format!("  [measure] [track \"{}\"] ({} beats) {}",
-                    measure.track, measure.count_beats(),
+                    self.track_label(measure.track), measure.count_beats(),
                     measure.notes.iter().map(|n| n.note_literal.literal.clone())
                     .collect::<Vec<_>>().join(" "));
                 sections.push(s);
@@ -46,6 +76,15 @@ pub struct Composition
     pub sections: Vec<Section>
 }
 
+impl Composition
+{
+    // the part a track was last declared as, if any
+    pub fn part_name(&self, track_id: u32) -> Option<&String>
+    {
+        self.sections.iter().rev().find_map(|s| s.parts.get(&track_id))
+    }
+}
+
 struct CompositionState
 {
     tempo: u16,
@@ -53,7 +92,13 @@ struct CompositionState
     scale: Scale,
     time_signature: Option<(Literal, TimeSignature)>,
     tone_id: ToneId,
-    track: u32
+    chord: Vec<ToneId>,
+    track: u32,
+    track_attributes: HashMap<u32, TrackAttributes>,
+    // declared parts, by name
+    parts: HashMap<String, (Literal, u32)>,
+    voice: Option<Voice>,
+    track_voices: HashMap<u32, Voice>
 }
 
 impl CompositionState
@@ -67,26 +112,45 @@ impl CompositionState
             scale: Scale::cmajor(),
             time_signature: None,
             tone_id: ToneId(13), // TODO
-            track: 0
+            chord: vec![],
+            track: 0,
+            track_attributes: HashMap::new(),
+            parts: HashMap::new(),
+            voice: None,
+            track_voices: HashMap::new()
         }
     }
 }
 
-fn assert_consistent_measure_counts(section: &Section) -> CompileResult<()>
+fn assert_consistent_measure_counts(section: &Section, errors: &mut Vec<CompileError>)
 {
+    let mut track_ids : Vec<&u32> = section.tracks.keys().collect();
+    track_ids.sort();
+
+    // with polymeter, measure counts may differ; the tracks must instead
+    // take equally long
+    if !section.track_time_signatures.is_empty()
+    {
+        assert_consistent_durations(section, &track_ids, errors);
+        return;
+    }
+
     let mut baseline = None;
-    for (track_id, measures) in &section.tracks
+    for track_id in track_ids
     {
+        let measures = &section.tracks[track_id];
         if measures.is_empty()
         {
-            return Err(CompileError::EmptyTrack(*track_id))
+            errors.push(CompileError::EmptyTrack(section.track_label(*track_id)));
+            continue;
         }
 
         if let Some((btid, count)) = baseline
         {
             if measures.len() != count
             {
-                return Err(CompileError::DifferingMeasureCounts(btid, count, *track_id, measures.len()));
+                errors.push(CompileError::DifferingMeasureCounts(section.track_label(btid), count,
+                    section.track_label(*track_id), measures.len()));
             }
         }
         else
@@ -94,11 +158,341 @@ fn assert_consistent_measure_counts(section: &Section) -> CompileResult<()>
             baseline = Some((*track_id, measures.len()));
         }
     }
+}
+
+fn assert_consistent_durations(section: &Section, track_ids: &[&u32], errors: &mut Vec<CompileError>)
+{
+    let mut baseline = None;
+    for track_id in track_ids
+    {
+        let measures = &section.tracks[track_id];
+        if measures.is_empty()
+        {
+            errors.push(CompileError::EmptyTrack(section.track_label(**track_id)));
+            continue;
+        }
+
+        let beats : Fraction = measures.iter().map(|m| m.count_beats()).sum();
+        let quarters = beats * section.unit_quarters();
+        if let Some((btid, bquarters)) = baseline
+        {
+            if quarters != bquarters
+            {
+                errors.push(CompileError::DifferingTrackDurations(section.track_label(btid), bquarters,
+                    section.track_label(**track_id), quarters));
+            }
+        }
+        else
+        {
+            baseline = Some((**track_id, quarters));
+        }
+    }
+}
+
+// rescales a measure written in another meter's units into the section's
+fn rescale_measure(measure: &mut Measure, factor: Fraction)
+{
+    for n in &mut measure.notes
+    {
+        n.note.beats = n.note.beats * factor;
+    }
+    for (offset, _, _) in &mut measure.tempo_marks
+    {
+        *offset = *offset * factor;
+    }
+    for (offset, _, _) in &mut measure.dynamic_marks
+    {
+        *offset = *offset * factor;
+    }
+}
+
+fn expand_repeats(measures: &[Measure]) -> CompileResult<Vec<Measure>>
+{
+    let mut expanded: Vec<Measure> = vec![];
+    let mut open_repeat: Option<(usize, &Measure)> = None;
+
+    for meas in measures
+    {
+        if meas.open
+        {
+            if let Some((_, outer)) = open_repeat
+            {
+                return Err(CompileError::NestedRepeat
+                {
+                    outer: outer.start.clone(),
+                    inner: meas.start.clone()
+                });
+            }
+            open_repeat = Some((expanded.len(), meas));
+        }
+
+        expanded.push(meas.clone());
+
+        if meas.close
+        {
+            let (begin, _) = open_repeat.take().ok_or(
+                CompileError::UnopenedRepeat(meas.end.clone()))?;
+
+            if meas.repeats == 0
+            {
+                return Err(CompileError::InvalidRepeatCount(meas.end.clone()));
+            }
+
+            let block = expanded[begin..].to_vec();
+            for _ in 1..meas.repeats
+            {
+                expanded.extend(block.iter().cloned());
+            }
+        }
+    }
+
+    if let Some((_, meas)) = open_repeat
+    {
+        return Err(CompileError::UnclosedRepeat(meas.start.clone()));
+    }
+
+    Ok(expanded)
+}
+
+fn check_ties(measures: &[Measure], errors: &mut Vec<CompileError>)
+{
+    let notes : Vec<&NoteDecl> = measures.iter().flat_map(|m| m.notes.iter()).collect();
+    for (i, n) in notes.iter().enumerate()
+    {
+        if !n.note.tie
+        {
+            continue;
+        }
+
+        match notes.get(i + 1)
+        {
+            None => errors.push(CompileError::UnterminatedTie(n.note_literal.clone())),
+            Some(next) =>
+            {
+                // the tied notes are sung as one, so the syllable carries
+                // over too; only its ending may change
+                if next.note.prefix != n.note.prefix || next.tone_id != n.tone_id || next.chord != n.chord
+                {
+                    errors.push(CompileError::MismatchedTie
+                    {
+                        tie: n.note_literal.clone(),
+                        next: next.note_literal.clone()
+                    });
+                }
+            }
+        }
+    }
+}
+
+// Splits a track containing chords into one list of measures per voice.
+// The first voice sings each note's own pitch; voice k sings the k-th
+// chord tone, resting wherever a chord has fewer tones than that.
+pub fn fan_out_chords(measures: &[Measure]) -> Vec<Vec<Measure>>
+{
+    let voices = measures.iter().flat_map(|m| m.notes.iter())
+        .map(|n| n.chord.len() + 1).max().unwrap_or(1);
+
+    (0..voices).map(|voice|
+    {
+        measures.iter().map(|meas|
+        {
+            let notes = meas.notes.iter().map(|n|
+            {
+                let mut decl = n.clone();
+                decl.chord = vec![];
+                if voice > 0
+                {
+                    match n.chord.get(voice - 1)
+                    {
+                        Some(tone_id) => decl.tone_id = *tone_id,
+                        None =>
+                        {
+                            decl.note.prefix = "_".to_string();
+                            decl.note.suffix = "".to_string();
+                            decl.note.tie = false;
+                        }
+                    }
+                }
+                decl
+            })
+            .collect();
+            Measure { notes, ..meas.clone() }
+        })
+        .collect()
+    })
+    .collect()
+}
+
+fn resolve_pitch(node: &StaffNode, scale: &Scale, errors: &mut Vec<CompileError>) -> Option<ToneId>
+{
+    match node
+    {
+        StaffNode::AbsolutePitch { literal: _, pitch } => Some(*pitch),
+        StaffNode::ScaleDegree { literal, degree, accidental } =>
+        {
+            let tone = sample_scale(scale, *degree, *accidental);
+            if tone.is_none()
+            {
+                errors.push(CompileError::PitchOutOfRange(literal.clone()));
+            }
+            tone
+        },
+        _ => None,
+    }
+}
+
+// Tempo marks apply to every track in the section, so they're gathered
+// from all of them by beat. A RIT or ACCEL runs until the next tempo mark,
+// which gives the tempo it arrives at.
+fn build_tempo_map(tempo: u16, pulse: u8, tracks: &TrackMap, errors: &mut Vec<CompileError>) -> TempoMap
+{
+    let mut marks: Vec<(Fraction, Literal, TempoMark)> = vec![];
+    for measures in tracks.values()
+    {
+        let mut start = Fraction::from(0);
+        for meas in measures
+        {
+            for (offset, literal, mark) in &meas.tempo_marks
+            {
+                marks.push((start + *offset, literal.clone(), mark.clone()));
+            }
+            start += meas.count_beats();
+        }
+    }
+    marks.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.idno.cmp(&b.1.idno)));
+
+    let mut map = TempoMap::new(tempo, pulse);
+    let mut bpm = tempo;
+    let mut ramp: Option<(Fraction, Literal, TempoRamp)> = None;
+    let mut previous: Option<(Fraction, Literal, TempoMark)> = None;
+
+    for (beat, literal, mark) in marks
+    {
+        if let Some((prev_beat, prev_literal, prev_mark)) = &previous
+        {
+            if *prev_beat == beat
+            {
+                if *prev_mark != mark
+                {
+                    errors.push(CompileError::ConflictingTempoMarks
+                    {
+                        first: prev_literal.clone(),
+                        second: literal.clone()
+                    });
+                }
+                continue;
+            }
+        }
+        previous = Some((beat, literal.clone(), mark.clone()));
+
+        match mark
+        {
+            TempoMark::Set(0) =>
+            {
+                // drop any ramp into it too, rather than also call it unterminated
+                errors.push(CompileError::ZeroTempo(literal));
+                ramp = None;
+            },
+            TempoMark::Set(target) =>
+            {
+                if let Some((start, ramp_literal, kind)) = ramp.take()
+                {
+                    let arrives = match kind
+                    {
+                        TempoRamp::Ritardando => target < bpm,
+                        TempoRamp::Accelerando => target > bpm,
+                    };
+                    if !arrives
+                    {
+                        errors.push(CompileError::MismatchedTempoRamp
+                        {
+                            ramp: ramp_literal,
+                            target: literal.clone()
+                        });
+                    }
+                    map.push(start, bpm, target);
+                }
+                map.push(beat, target, target);
+                bpm = target;
+            },
+            TempoMark::Ramp(kind) =>
+            {
+                if let Some((_, ramp_literal, _)) = ramp.replace((beat, literal, kind))
+                {
+                    errors.push(CompileError::UnterminatedTempoRamp(ramp_literal));
+                }
+            },
+        }
+    }
+
+    if let Some((_, ramp_literal, _)) = ramp
+    {
+        errors.push(CompileError::UnterminatedTempoRamp(ramp_literal));
+    }
+
+    map
+}
+
+// Unlike tempo, dynamics belong to a single track. A CRESC or DIM runs
+// until the next dynamic mark, which gives the level it arrives at.
+fn build_dynamic_map(level: &DynamicLevel, measures: &[Measure], errors: &mut Vec<CompileError>) -> DynamicMap
+{
+    let mut map = DynamicMap::constant(level.clone());
+    let mut current = level.clone();
+    let mut hairpin: Option<(Fraction, Literal, Hairpin)> = None;
+    let mut start = Fraction::from(0);
+
+    for meas in measures
+    {
+        for (offset, literal, mark) in &meas.dynamic_marks
+        {
+            let beat = start + *offset;
+            match mark
+            {
+                DynamicMark::Set(target) =>
+                {
+                    if let Some((from, hairpin_literal, kind)) = hairpin.take()
+                    {
+                        let arrives = match kind
+                        {
+                            Hairpin::Crescendo => *target > current,
+                            Hairpin::Decrescendo => *target < current,
+                        };
+                        if !arrives
+                        {
+                            errors.push(CompileError::MismatchedHairpin
+                            {
+                                hairpin: hairpin_literal,
+                                target: literal.clone()
+                            });
+                        }
+                        map.push(from, current.clone(), target.clone());
+                    }
+                    map.push(beat, target.clone(), target.clone());
+                    current = target.clone();
+                },
+                DynamicMark::Hairpin(kind) =>
+                {
+                    if let Some((_, hairpin_literal, _)) = hairpin.replace((beat, literal.clone(), *kind))
+                    {
+                        errors.push(CompileError::UnterminatedHairpin(hairpin_literal));
+                    }
+                },
+            }
+        }
+        start += meas.count_beats();
+    }
+
+    if let Some((_, hairpin_literal, _)) = hairpin
+    {
+        errors.push(CompileError::UnterminatedHairpin(hairpin_literal));
+    }
 
-    Ok(())
+    map
 }
 
-fn make_section(id: u32, section: &SectionNode, state: &mut CompositionState) -> CompileResult<Section>
+fn make_section(id: u32, section: &SectionNode, state: &mut CompositionState,
+    errors: &mut Vec<CompileError>) -> Section
 {
     for node in &section.preamble
     {
@@ -116,19 +510,59 @@ fn make_section(id: u32, section: &SectionNode, state: &mut CompositionState) ->
             {
                 state.time_signature = Some((literal.clone(), ratio.clone()));
             },
-            PreambleNode::Tempo { literal: _, tempo } =>
+            PreambleNode::Tempo { literal, tempo } =>
             {
+                if *tempo == 0
+                {
+                    errors.push(CompileError::ZeroTempo(literal.clone()));
+                    continue;
+                }
                 state.tempo = tempo.clone();
             }
+            PreambleNode::Voice { literal: _, voice } =>
+            {
+                state.voice = Some(*voice);
+            }
+            PreambleNode::Part { literal, name, track_id } =>
+            {
+                // a part names one track, and a track has one part
+                let same_name = state.parts.get(name);
+                let same_track = state.parts.iter().find(|(n, (_, id))| id == track_id && *n != name);
+                match (same_name, same_track)
+                {
+                    (Some((first, id)), _) if id != track_id =>
+                    {
+                        errors.push(CompileError::ConflictingParts { first: first.clone(), second: literal.clone() });
+                    },
+                    (_, Some((_, (first, _)))) =>
+                    {
+                        errors.push(CompileError::ConflictingParts { first: first.clone(), second: literal.clone() });
+                    },
+                    (Some(_), None) => (),
+                    (None, None) =>
+                    {
+                        state.parts.insert(name.clone(), (literal.clone(), *track_id));
+                    },
+                }
+            }
             PreambleNode::Endline(_) => (),
         }
     }
 
     let mut tracks: TrackMap = TrackMap::new();
+    let mut track_time_signatures: HashMap<u32, (Literal, TimeSignature)> = HashMap::new();
+    let mut track_headers: HashMap<u32, (Literal, TrackAttributes)> = HashMap::new();
+    let mut track_voices: HashMap<u32, (Literal, Voice)> = HashMap::new();
+
+    // marks written ahead of a measure, e.g. "[1] 90BPM | ...", carry
+    // over to the start of the next one
+    let mut tempo_marks = vec![];
+    let mut dynamic_marks = vec![];
 
     for meas in &section.measures
     {
         let mut notes: Vec<NoteDecl> = vec![];
+        let mut offset = Fraction::from(0);
 
         for snode in &meas.staff
         {
@@ -140,26 +574,119 @@ fn make_section(id: u32, section: &SectionNode, state: &mut CompositionState) ->
                     {
                         note: note.clone(),
                         note_literal: literal.clone(),
-                        tone_id: state.tone_id
+                        tone_id: state.tone_id,
+                        chord: state.chord.clone()
                     };
+                    offset += note.beats;
                     notes.push(n);
                 },
-                StaffNode::AbsolutePitch { literal: _, pitch } =>
+                StaffNode::Tempo { literal, mark } =>
                 {
-                    state.tone_id = *pitch;
+                    tempo_marks.push((offset, literal.clone(), mark.clone()));
                 },
-                StaffNode::ScaleDegree { literal: _, degree } =>
+                StaffNode::Dynamic { literal, mark } =>
                 {
-                    state.tone_id = sample_scale(&state.scale, *degree);
+                    dynamic_marks.push((offset, literal.clone(), mark.clone()));
                 },
-                StaffNode::Track { literal: _, track_id } =>
+                StaffNode::AbsolutePitch { .. } |
+                StaffNode::ScaleDegree { .. } =>
                 {
-                    state.track = track_id.clone();
+                    if let Some(tone_id) = resolve_pitch(snode, &state.scale, errors)
+                    {
+                        state.tone_id = tone_id;
+                    }
+                    state.chord = vec![];
+                },
+                StaffNode::Chord { literal: _, pitches } =>
+                {
+                    let tones : Vec<ToneId> = pitches.iter()
+                        .filter_map(|p| resolve_pitch(p, &state.scale, errors)).collect();
+                    if tones.len() < pitches.len()
+                    {
+                        continue;
+                    }
+                    state.tone_id = tones[0];
+                    state.chord = tones[1..].to_vec();
+                },
+                StaffNode::Track { literal, track, attributes } =>
+                {
+                    let track_id = match track
+                    {
+                        TrackRef::Id(track_id) => track_id,
+                        TrackRef::Part(name) => match state.parts.get(name)
+                        {
+                            Some((_, track_id)) => track_id,
+                            None =>
+                            {
+                                errors.push(CompileError::UnknownPart(literal.clone()));
+                                continue;
+                            }
+                        },
+                    };
+                    state.track = *track_id;
+                    if *attributes == TrackAttributes::default()
+                    {
+                        continue;
+                    }
+                    match track_headers.get_mut(track_id)
+                    {
+                        Some((first, existing)) if existing.conflicts_with(attributes) =>
+                        {
+                            errors.push(CompileError::ConflictingTrackAttributes
+                            {
+                                first: first.clone(),
+                                second: literal.clone()
+                            });
+                        },
+                        Some((_, existing)) => existing.merge(attributes),
+                        None =>
+                        {
+                            track_headers.insert(*track_id, (literal.clone(), attributes.clone()));
+                        }
+                    }
+                },
+                StaffNode::TimeSignature { literal, ratio } =>
+                {
+                    match track_time_signatures.get(&state.track)
+                    {
+                        Some((first, existing)) if existing != ratio =>
+                        {
+                            errors.push(CompileError::ConflictingTimeSignatures
+                            {
+                                first: first.clone(),
+                                second: literal.clone()
+                            });
+                        },
+                        Some(_) => (),
+                        None =>
+                        {
+                            track_time_signatures.insert(state.track, (literal.clone(), ratio.clone()));
+                        }
+                    }
+                },
+                StaffNode::Voice { literal, voice } =>
+                {
+                    match track_voices.get(&state.track)
+                    {
+                        Some((first, existing)) if existing != voice =>
+                        {
+                            errors.push(CompileError::ConflictingVoices
+                            {
+                                first: first.clone(),
+                                second: literal.clone()
+                            });
+                        },
+                        Some(_) => (),
+                        None =>
+                        {
+                            track_voices.insert(state.track, (literal.clone(), *voice));
+                        }
+                    }
                 },
                 StaffNode::MeasureBar { literal, .. } |
                 StaffNode::Endline { literal } =>
                 {
-                    return Err(CompileError::InvalidSyntax(literal.clone()));
+                    errors.push(CompileError::InvalidSyntax(literal.clone()));
                 },
             }
         }
@@ -177,13 +704,11 @@ fn make_section(id: u32, section: &SectionNode, state: &mut CompositionState) ->
         {
             false
         };
-        let close = if let Token::MeasureBar(close, _) = meas.end.1
-        {
-            close
-        }
-        else
+        let (close, repeats) = match meas.end.1
         {
-            false
+            Token::MeasureBar(close, _) => (close, if close { 2 } else { 1 }),
+            Token::EndRepeat(count) => (true, count),
+            _ => (false, 1),
         };
 
         let m = Measure
@@ -192,8 +717,11 @@ fn make_section(id: u32, section: &SectionNode, state: &mut CompositionState) ->
             end: meas.end.0.clone(),
             close,
             open,
+            repeats,
             track: state.track.clone(),
-            notes
+            notes,
+            tempo_marks: std::mem::take(&mut tempo_marks),
+            dynamic_marks: std::mem::take(&mut dynamic_marks)
         };
 
         if tracks.get(&m.track).is_none()
@@ -204,46 +732,111 @@ fn make_section(id: u32, section: &SectionNode, state: &mut CompositionState) ->
         tracks.get_mut(&m.track).unwrap().push(m);
     }
 
-    if let Some(ts) = &state.time_signature
+    for (track_id, measures) in &tracks
     {
-        for (track_id, measures) in &tracks
+        let ts = match track_time_signatures.get(track_id).or(state.time_signature.as_ref())
+        {
+            Some(ts) => ts,
+            None => continue,
+        };
+        for meas in measures
         {
-            for meas in measures
+            let beats = meas.count_beats();
+            if beats == Fraction::new(0u64, 1u64)
             {
-                let beats = meas.count_beats();
-                if beats == Fraction::new(0u64, 1u64)
-                {
-                    continue;
-                }
+                continue;
+            }
 
-                let dirty = Fraction::new(ts.1.0, 1u64);
-                if beats != dirty
+            // both sides count units of the denominator
+            let nominal = Fraction::new(ts.1.0, 1u64);
+            if beats != nominal
+            {
+                errors.push(CompileError::TimeSignatureViolation
                 {
-                    return Err(CompileError::TimeSignatureViolation
-                    {
-                        measure: meas.clone(),
-                        time_signature: ts.0.clone(),
-                        nominal: ts.1
-                    });
-                }
+                    measure: meas.clone(),
+                    time_signature: ts.0.clone(),
+                    nominal: ts.1
+                });
             }
         }
     }
 
+    let section_units = state.time_signature.as_ref()
+        .map(|(_, ts)| unit_quarters(ts)).unwrap_or(Fraction::from(1));
+    for (track_id, (_, ts)) in &track_time_signatures
+    {
+        let factor = unit_quarters(ts) / section_units;
+        if let Some(measures) = tracks.get_mut(track_id)
+        {
+            measures.iter_mut().for_each(|m| rescale_measure(m, factor));
+        }
+    }
+
+    for measures in tracks.values_mut()
+    {
+        match expand_repeats(measures)
+        {
+            Ok(expanded) => *measures = expanded,
+            Err(e) => errors.push(e),
+        }
+    }
+
+    for measures in tracks.values()
+    {
+        check_ties(measures, errors);
+    }
+
+    let pulse = state.time_signature.as_ref().map(|(_, ts)| pulse_units(ts)).unwrap_or(1);
+    let tempo_map = build_tempo_map(state.tempo, pulse, &tracks, errors);
+    let tempo = state.tempo;
+    state.tempo = tempo_map.final_bpm();
+
+    // headers carry over into later sections, each only overriding what
+    // it gives
+    for (track_id, (_, attributes)) in &track_headers
+    {
+        state.track_attributes.entry(*track_id).or_default().merge(attributes);
+    }
+    // a track's own voice carries over too, and wins over the section's
+    state.track_voices.extend(track_voices.iter().map(|(track_id, (_, voice))| (*track_id, *voice)));
+    let voices = tracks.keys().filter_map(|track_id|
+    {
+        state.track_voices.get(track_id).cloned().or(state.voice).map(|voice| (*track_id, voice))
+    })
+    .collect();
+
+    let track_attributes = tracks.keys().map(|track_id|
+    {
+        (*track_id, state.track_attributes.get(track_id).cloned().unwrap_or_default())
+    })
+    .collect();
+
+    let dynamics = tracks.iter().map(|(track_id, measures)|
+    {
+        (*track_id, build_dynamic_map(&state.dynamic, measures, errors))
+    })
+    .collect();
+
     let s = Section
     {
         id,
         name: section.name.clone(),
-        tempo: state.tempo.clone(),
+        tempo,
+        tempo_map,
         dynamic: state.dynamic.clone(),
+        dynamics,
         scale: state.scale.clone(),
         time_signature: state.time_signature.clone(),
+        track_time_signatures,
+        track_attributes,
+        parts: state.parts.iter().map(|(name, (_, track_id))| (*track_id, name.clone())).collect(),
+        voices,
         tracks
     };
 
-    assert_consistent_measure_counts(&s)?;
+    assert_consistent_measure_counts(&s, errors);
 
-    return Ok(s)
+    return s
 }
 
 pub fn do_semantics(tree: &AST) -> CompileResult<Composition>
@@ -251,11 +844,375 @@ pub fn do_semantics(tree: &AST) -> CompileResult<Composition>
     let mut state = CompositionState::defaults();
 
     let mut sections = vec![];
+    let mut errors = vec![];
     for (id, node) in tree.iter().enumerate()
     {
-        let s = make_section(id as u32, node, &mut state)?;
+        let s = make_section(id as u32, node, &mut state, &mut errors);
         sections.push(s);
     }
 
-    Ok(Composition{ sections })
+    collect_diagnostics(Composition{ sections }, errors)
+}
+
+#[cfg(test)]
+pub(crate) fn semantics_of(source: &str) -> CompileResult<Composition>
+{
+    let tokens = crate::lexer::lex_multiline_string(source)?;
+    let tree = parse_to_ast(&tokens)?;
+    do_semantics(&tree)
+}
+
+#[cfg(test)]
+fn track_literals(comp: &Composition, track: u32) -> Vec<String>
+{
+    comp.sections[0].tracks[&track].iter().map(|m|
+    {
+        m.notes.iter().map(|n| n.note_literal.literal.clone())
+            .collect::<Vec<_>>().join(" ")
+    })
+    .collect()
+}
+
+#[test]
+fn repeat_expansion()
+{
+    let comp = semantics_of("[1] | a | b |: c | d :| e |").unwrap();
+    assert_eq!(track_literals(&comp, 1), vec!["a", "b", "c", "d", "c", "d", "e"]);
+
+    let comp = semantics_of("[1] |: a :|x3 b |").unwrap();
+    assert_eq!(track_literals(&comp, 1), vec!["a", "a", "a", "b"]);
+
+    let comp = semantics_of("[1] |: a :|: b :| c |").unwrap();
+    assert_eq!(track_literals(&comp, 1), vec!["a", "a", "b", "b", "c"]);
+
+    let comp = semantics_of("[1] |: a | b :|x1").unwrap();
+    assert_eq!(track_literals(&comp, 1), vec!["a", "b"]);
+}
+
+#[test]
+fn repeat_errors()
+{
+    assert!(matches!(semantics_of("[1] |: a |: b :| c :|"),
+        Err(CompileError::NestedRepeat { .. })));
+    assert!(matches!(semantics_of("[1] |: a | b |"),
+        Err(CompileError::UnclosedRepeat(_))));
+    assert!(matches!(semantics_of("[1] | a | b :|"),
+        Err(CompileError::UnopenedRepeat(_))));
+    assert!(matches!(semantics_of("[1] |: a :|x0"),
+        Err(CompileError::InvalidRepeatCount(_))));
+}
+
+#[test]
+fn multiple_semantic_errors()
+{
+    let result = semantics_of(indoc::indoc! {"
+        4/4
+        [1] | . . . | . . . . | . . . . . |
+        ======
+        [1] |: . . . . |
+    "});
+
+    match result
+    {
+        Err(CompileError::Diagnostics(errors)) =>
+        {
+            assert_eq!(errors.len(), 3);
+            assert!(matches!(errors[0], CompileError::TimeSignatureViolation { .. }));
+            assert!(matches!(errors[1], CompileError::TimeSignatureViolation { .. }));
+            assert!(matches!(errors[2], CompileError::UnclosedRepeat(_)));
+        },
+        other => panic!("expected three diagnostics, got {:?}", other),
+    }
+}
+
+#[test]
+fn accidental_degrees()
+{
+    let comp = semantics_of("4/4 [1] | 4# ah 7b ah {1 3b 5} ah 5 ah |").unwrap();
+    let notes = &comp.sections[0].tracks[&1][0].notes;
+    let tones : Vec<u8> = notes.iter().map(|n| n.tone_id.0).collect();
+    assert_eq!(tones, vec![19, 23, 13, 20]);
+    assert_eq!(notes[2].chord, vec![ToneId(16), ToneId(20)]);
+}
+
+#[test]
+fn scale_degree_range()
+{
+    assert!(matches!(semantics_of("C2MAJOR [1] | 1b ah |"),
+        Err(CompileError::PitchOutOfRange(l)) if l.literal == "1b"));
+    assert!(matches!(semantics_of("C4MAJOR [1] | 15 ah |"),
+        Err(CompileError::PitchOutOfRange(l)) if l.literal == "15"));
+    assert!(matches!(semantics_of("C4MAJOR [1] | {1 3 15} ah |"),
+        Err(CompileError::PitchOutOfRange(l)) if l.literal == "15"));
+
+    // the top of the range is still reachable
+    let comp = semantics_of("C4MAJOR [1] | 8 ah 1b ah |").unwrap();
+    let tones : Vec<u8> = comp.sections[0].tracks[&1][0].notes.iter().map(|n| n.tone_id.0).collect();
+    assert_eq!(tones, vec![MAX_TONE, 24]);
+}
+
+#[test]
+fn chord_fan_out()
+{
+    let comp = semantics_of("4/4 [1] | {1 3 5} ah:2 {1 5} oh:2 | 2 ah:4 |").unwrap();
+    let voices = fan_out_chords(&comp.sections[0].tracks[&1]);
+    assert_eq!(voices.len(), 3);
+
+    let tones = |voice: &Vec<Measure>| -> Vec<(String, u8)>
+    {
+        voice.iter().flat_map(|m| m.notes.iter())
+            .map(|n| (n.note.prefix.clone(), n.tone_id.0)).collect()
+    };
+
+    let ah = |t: u8| ("ah".to_string(), t);
+    let oh = |t: u8| ("oh".to_string(), t);
+    let rest = |t: u8| ("_".to_string(), t);
+
+    assert_eq!(tones(&voices[0]), vec![ah(13), oh(13), ah(15)]);
+    assert_eq!(tones(&voices[1]), vec![ah(17), oh(20), rest(15)]);
+    assert_eq!(tones(&voices[2]), vec![ah(20), rest(13), rest(15)]);
+    assert!(voices.iter().all(|v| v.len() == 2 && v[0].count_beats() == Fraction::from(4)));
+
+    let comp = semantics_of("[1] | 1 ah | 5 oh |").unwrap();
+    assert_eq!(fan_out_chords(&comp.sections[0].tracks[&1]).len(), 1);
+}
+
+#[test]
+fn inline_tempo_marks()
+{
+    let comp = semantics_of(indoc::indoc! {"
+        60BPM 4/4
+        [1] | . . . . | . . RIT . . | 30BPM . . . . |
+        [2] | - - - - | - - - - | - - 120BPM - - |
+        ======
+        [1] | . . . . |
+    "}).unwrap();
+
+    let map = &comp.sections[0].tempo_map;
+    let starts : Vec<(Fraction, u16, u16)> = map.segments().iter()
+        .map(|s| (s.start, s.from_bpm, s.to_bpm)).collect();
+    assert_eq!(starts, vec![
+        (Fraction::from(0), 60, 60),
+        (Fraction::from(6), 60, 30),
+        (Fraction::from(8), 30, 30),
+        (Fraction::from(10), 120, 120),
+    ]);
+
+    // the next section picks up where the last one left off
+    assert_eq!(comp.sections[1].tempo, 120);
+
+    // marks written before a measure land on its first beat
+    let comp = semantics_of("60BPM [1] | . . | 90BPM | . . |").unwrap();
+    assert_eq!(comp.sections[0].tempo_map.segments()[1].start, Fraction::from(2));
+}
+
+#[test]
+fn tempo_mark_errors()
+{
+    assert!(matches!(semantics_of("60BPM [1] | . RIT . . . |"),
+        Err(CompileError::UnterminatedTempoRamp(_))));
+    assert!(matches!(semantics_of("60BPM [1] | . RIT . 90BPM . . |"),
+        Err(CompileError::MismatchedTempoRamp { .. })));
+    assert!(matches!(semantics_of("60BPM [1] | . ACCEL . 50BPM . . |"),
+        Err(CompileError::MismatchedTempoRamp { .. })));
+    assert!(matches!(semantics_of("[1] | . 90BPM . |\n[2] | . 80BPM . |"),
+        Err(CompileError::ConflictingTempoMarks { .. })));
+    assert!(semantics_of("[1] | . 90BPM . |\n[2] | . 90BPM . |").is_ok());
+
+    assert!(matches!(semantics_of("60BPM [1] | . 0BPM . . |"),
+        Err(CompileError::ZeroTempo(l)) if l.literal == "0BPM"));
+    assert!(matches!(semantics_of("60BPM [1] | . RIT . 0BPM . |"),
+        Err(CompileError::ZeroTempo(l)) if l.literal == "0BPM"));
+    assert!(matches!(semantics_of("0BPM [1] | . . . . |"),
+        Err(CompileError::ZeroTempo(l)) if l.literal == "0BPM"));
+}
+
+#[test]
+fn hairpins()
+{
+    let comp = semantics_of(indoc::indoc! {"
+        PIANO 4/4
+        [1] | . . CRESC . . | FORTE . . . . |
+        [2] | . . . . | . . DIM . PIANISSIMO . |
+    "}).unwrap();
+
+    let segments = |track: u32| -> Vec<(Fraction, DynamicLevel, DynamicLevel)>
+    {
+        comp.sections[0].dynamics[&track].segments().iter()
+            .map(|s| (s.start, s.from.clone(), s.to.clone())).collect()
+    };
+
+    assert_eq!(segments(1), vec![
+        (Fraction::from(0), DynamicLevel::Piano, DynamicLevel::Piano),
+        (Fraction::from(2), DynamicLevel::Piano, DynamicLevel::Forte),
+        (Fraction::from(4), DynamicLevel::Forte, DynamicLevel::Forte),
+    ]);
+    assert_eq!(segments(2), vec![
+        (Fraction::from(0), DynamicLevel::Piano, DynamicLevel::Piano),
+        (Fraction::from(6), DynamicLevel::Piano, DynamicLevel::Pianissimo),
+        (Fraction::from(7), DynamicLevel::Pianissimo, DynamicLevel::Pianissimo),
+    ]);
+}
+
+#[test]
+fn hairpin_errors()
+{
+    assert!(matches!(semantics_of("[1] | . CRESC . . . |"),
+        Err(CompileError::UnterminatedHairpin(l)) if l.colno == 9));
+    assert!(matches!(semantics_of("[1] | . CRESC . DIM . PIANO . |"),
+        Err(CompileError::UnterminatedHairpin(l)) if l.colno == 9));
+    assert!(matches!(semantics_of("FORTE [1] | . CRESC . PIANO . . |"),
+        Err(CompileError::MismatchedHairpin { .. })));
+    assert!(matches!(semantics_of("PIANO [1] | . DIM . FORTE . . |"),
+        Err(CompileError::MismatchedHairpin { .. })));
+}
+
+#[test]
+fn compound_meter_tempo()
+{
+    let comp = semantics_of("60BPM 6/8 [1] | . . . . . . |").unwrap();
+    let section = &comp.sections[0];
+    assert_eq!(section.pulse_units(), 3);
+    assert_eq!(section.unit_quarters(), Fraction::new(1u64, 2u64));
+    assert_eq!(section.tempo_map.duration_ms(&Fraction::from(0), &Fraction::from(6)), 2000);
+
+    // a 6/8 measure needs six eighths, not three quarters' worth
+    assert!(semantics_of("3/4 [1] | . . . |").is_ok());
+    assert!(matches!(semantics_of("6/8 [1] | . . . |"),
+        Err(CompileError::TimeSignatureViolation { .. })));
+}
+
+#[test]
+fn polymeter()
+{
+    // three bars of 4/4 against four bars of 3/4
+    let comp = semantics_of(indoc::indoc! {"
+        4/4
+        [1] | . . . . | . . . . | . . . . |
+        [2] 3/4 | . . . | . . . | . . . | . . . |"}).unwrap();
+    let section = &comp.sections[0];
+    assert_eq!(section.tracks[&2].len(), 4);
+    assert!(section.track_time_signatures.contains_key(&2));
+
+    // a 6/8 track is rescaled into the section's quarter-note beats
+    let comp = semantics_of("3/4 [1] | . . . |\n[2] 6/8 | . . . . . . |").unwrap();
+    let beats : Fraction = comp.sections[0].tracks[&2].iter().map(|m| m.count_beats()).sum();
+    assert_eq!(beats, Fraction::from(3));
+
+    assert!(matches!(semantics_of("4/4 [1] | . . . . |\n[2] 3/4 | . . . | . . . |"),
+        Err(CompileError::DifferingTrackDurations(a, _, b, _)) if a == "1" && b == "2"));
+    assert!(matches!(semantics_of("4/4 [1] | . . . . |\n[2] 3/4 | . . . . |"),
+        Err(CompileError::TimeSignatureViolation { .. })));
+    assert!(matches!(semantics_of("[1] 3/4 | . . . |\n[1] 6/8 | . . . |"),
+        Err(CompileError::ConflictingTimeSignatures { .. })));
+}
+
+#[test]
+fn tie_validation()
+{
+    assert!(semantics_of("4/4 [1] | . . . ah~ | ah:2 - - |").is_ok());
+    assert!(semantics_of("4/4 [1] |: ah . . ah~ :| ah:2 - - |").is_ok());
+    assert!(semantics_of("4/4 [1] | . . . ah-n~ | ah-t:2 - - |").is_ok());
+
+    assert!(matches!(semantics_of("4/4 [1] | . . . ah~ | 5 ah:2 - - |"),
+        Err(CompileError::MismatchedTie { .. })));
+    assert!(matches!(semantics_of("4/4 [1] | . . . ah~ | - . . . |"),
+        Err(CompileError::MismatchedTie { .. })));
+    assert!(matches!(semantics_of("4/4 [1] | a b c ah~ | oh - - - |"),
+        Err(CompileError::MismatchedTie { .. })));
+    // the second time through, the tie runs into the repeat's first note
+    assert!(matches!(semantics_of("4/4 [1] |: . . . ah~ :| ah:2 - - |"),
+        Err(CompileError::MismatchedTie { .. })));
+    assert!(matches!(semantics_of("4/4 [1] | . . . ah~ |"),
+        Err(CompileError::UnterminatedTie(_))));
+
+    assert!(semantics_of("4/4 [1] | . . . {1 3} ah~ | ah:2 - - |").is_ok());
+    assert!(matches!(semantics_of("4/4 [1] | . . . {1 3} ah~ | 1 ah:2 - - |"),
+        Err(CompileError::MismatchedTie { .. })));
+}
+
+#[test]
+fn track_headers()
+{
+    let comp = semantics_of(indoc::indoc! {"
+        [1 gain=-3dB] | a | [1 pan=0.3] | b |
+        [2] | a | b |
+        ======
+        [1 pan=-0.5] | c |
+        [2 gain=-6dB] | c |
+        "}).unwrap();
+
+    let first = &comp.sections[0].track_attributes;
+    assert_eq!(first[&1], TrackAttributes { gain_db: Some(-3.0), pan: Some(0.3) });
+    assert_eq!(first[&2], TrackAttributes::default());
+
+    // later headers override only what they give
+    let second = &comp.sections[1].track_attributes;
+    assert_eq!(second[&1], TrackAttributes { gain_db: Some(-3.0), pan: Some(-0.5) });
+    assert_eq!(second[&2].gain_db, Some(-6.0));
+
+    assert!(semantics_of("[1 pan=0.3] | a | [1 pan=0.3] | b |").is_ok());
+    assert!(matches!(semantics_of("[1 pan=0.3] | a | [1 pan=-0.3] | b |"),
+        Err(CompileError::ConflictingTrackAttributes { .. })));
+}
+
+#[test]
+fn named_parts()
+{
+    let comp = semantics_of(indoc::indoc! {"
+        PART soprano = [1]
+        PART bass = [2]
+        [soprano] | a | b |
+        [bass] | a | b |
+        ======
+        [bass] | c |
+        [1] | c |
+        "}).unwrap();
+
+    // declarations carry over, and parts and numbers name the same track
+    assert_eq!(comp.sections[1].tracks[&1].len(), 1);
+    assert_eq!(comp.sections[1].track_label(2), "bass");
+    assert!(comp.sections[1].to_string().contains("[track \"soprano\"]"));
+
+    // diagnostics call tracks by their part
+    match semantics_of("PART alto = [3]\n[alto] | a | b |\n[1] | a |")
+    {
+        Err(CompileError::DifferingMeasureCounts(a, 1, b, 2)) => assert_eq!((a.as_str(), b.as_str()), ("1", "alto")),
+        other => panic!("{:?}", other),
+    }
+
+    assert!(matches!(semantics_of("[tenor] | a |"), Err(CompileError::UnknownPart(_))));
+    assert!(semantics_of("PART alto = [3]\nPART alto = [3]\n[alto] | a |").is_ok());
+    assert!(matches!(semantics_of("PART alto = [3]\nPART alto = [4]\n[alto] | a |"),
+        Err(CompileError::ConflictingParts { .. })));
+    assert!(matches!(semantics_of("PART alto = [3]\nPART tenor = [3]\n[alto] | a |"),
+        Err(CompileError::ConflictingParts { .. })));
+}
+
+#[test]
+fn voice_selection()
+{
+    let comp = semantics_of(indoc::indoc! {"
+        VOICE harry
+        [1] VOICE betty | a |
+        [2] | a |
+        [3] 1/4 VOICE kit | a |
+        ======
+        VOICE frank
+        [1] | b |
+        [2] | b |
+        [4] | b |
+        "}).unwrap();
+
+    let first = &comp.sections[0].voices;
+    assert_eq!((first[&1], first[&2], first[&3]), (Voice::Betty, Voice::Harry, Voice::Kit));
+
+    // a track's own voice outlasts the section it was chosen in
+    let second = &comp.sections[1].voices;
+    assert_eq!((second[&1], second[&2], second[&4]), (Voice::Betty, Voice::Frank, Voice::Frank));
+
+    assert!(semantics_of("[1] | a |").unwrap().sections[0].voices.is_empty());
+    assert!(matches!(semantics_of("[1] VOICE paul | a | [1] VOICE rita | b |"),
+        Err(CompileError::ConflictingVoices { .. })));
+    assert!(semantics_of("[1] | a VOICE paul b |").is_err());
 }
@@ -0,0 +1,258 @@
+use crate::moonbase::{parse_moonbase_str, MoonbaseNote, MoonbaseResult, SAMPLE_RATE, NOTE_BIAS_MS, TAIL_SAMPLES};
+use crate::tts::TtsBackend;
+use std::f64::consts::{PI, TAU};
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SynthVoice
+{
+    Tone,
+    Formant
+}
+
+// first three formant frequencies (Hz) of each vowel the engine knows,
+// roughly those of an adult male speaker
+static FORMANT_MAP : [(&str, [f64; 3]); 17] =
+[
+    ("aa", [730.0, 1090.0, 2440.0]),
+    ("ae", [660.0, 1720.0, 2410.0]),
+    ("ah", [520.0, 1190.0, 2390.0]),
+    ("ao", [570.0,  840.0, 2410.0]),
+    ("aw", [730.0, 1090.0, 2440.0]),
+    ("ax", [500.0, 1500.0, 2500.0]),
+    ("ay", [730.0, 1090.0, 2440.0]),
+    ("eh", [530.0, 1840.0, 2480.0]),
+    ("er", [490.0, 1350.0, 1690.0]),
+    ("ey", [480.0, 1900.0, 2500.0]),
+    ("ih", [390.0, 1990.0, 2550.0]),
+    ("iy", [270.0, 2290.0, 3010.0]),
+    ("ow", [500.0,  900.0, 2400.0]),
+    ("oy", [570.0,  840.0, 2410.0]),
+    ("uh", [440.0, 1020.0, 2240.0]),
+    ("uw", [300.0,  870.0, 2240.0]),
+    ("yu", [300.0,  870.0, 2240.0]),
+];
+
+static FORMANT_BANDWIDTHS : [f64; 3] = [60.0, 90.0, 120.0];
+static FORMANT_GAINS : [f64; 3] = [1.0, 0.5, 0.25];
+
+const AMPLITUDE: f64 = 8000.0;
+const RAMP_MS: usize = 10;
+
+fn vowel_formants(prefix: &str) -> [f64; 3]
+{
+    // the last vowel in the syllable is the one that gets sung
+    FORMANT_MAP.iter()
+        .filter_map(|(v, f)| prefix.rfind(v).map(|i| (i, f)))
+        .max_by_key(|(i, _)| *i)
+        .map(|(_, f)| *f)
+        .unwrap_or([520.0, 1190.0, 2390.0])
+}
+
+#[test]
+fn vowel_lookup()
+{
+    assert_eq!(vowel_formants("duw"), [300.0, 870.0, 2240.0]);
+    assert_eq!(vowel_formants("nah"), [520.0, 1190.0, 2390.0]);
+    assert_eq!(vowel_formants("bae"), [660.0, 1720.0, 2410.0]);
+    assert_eq!(vowel_formants("ihiy"), [270.0, 2290.0, 3010.0]);
+    assert_eq!(vowel_formants("mm"), [520.0, 1190.0, 2390.0]);
+}
+
+// two-pole resonator, as used in classic cascade/parallel formant synths
+struct Resonator
+{
+    a1: f64,
+    a2: f64,
+    gain: f64,
+    y1: f64,
+    y2: f64
+}
+
+impl Resonator
+{
+    fn new(freq: f64, bandwidth: f64) -> Self
+    {
+        let fs = SAMPLE_RATE as f64;
+        let r = (-PI * bandwidth / fs).exp();
+        Resonator
+        {
+            a1: 2.0 * r * (TAU * freq / fs).cos(),
+            a2: -r * r,
+            gain: 1.0 - r,
+            y1: 0.0,
+            y2: 0.0
+        }
+    }
+
+    fn step(&mut self, x: f64) -> f64
+    {
+        let y = self.gain * x + self.a1 * self.y1 + self.a2 * self.y2;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+fn envelope(i: usize, count: usize) -> f64
+{
+    let ramp = (SAMPLE_RATE as usize * RAMP_MS / 1000).min(count / 2).max(1);
+    let edge = i.min(count - 1 - i);
+    (edge as f64 / ramp as f64).min(1.0)
+}
+
+fn render_note(note: &MoonbaseNote, count: usize, voice: SynthVoice, out: &mut Vec<i16>)
+{
+    if note.prefix == "_"
+    {
+        out.extend(std::iter::repeat(0).take(count));
+        return;
+    }
+
+    let fs = SAMPLE_RATE as f64;
+    let f0 = note.tone_id.frequency();
+
+    match voice
+    {
+        SynthVoice::Tone =>
+        {
+            for i in 0..count
+            {
+                let x = (TAU * f0 * i as f64 / fs).sin();
+                out.push((x * envelope(i, count) * AMPLITUDE) as i16);
+            }
+        },
+        SynthVoice::Formant =>
+        {
+            let formants = vowel_formants(&note.prefix);
+            let mut resonators : Vec<Resonator> = formants.iter()
+                .zip(FORMANT_BANDWIDTHS.iter())
+                .map(|(f, bw)| Resonator::new(*f, *bw))
+                .collect();
+
+            // resonator output is much quieter than its input; this brings
+            // a sawtooth source back up to roughly the tone voice's level
+            let makeup = 4.0;
+
+            for i in 0..count
+            {
+                let phase = (f0 * i as f64 / fs).fract();
+                let source = 1.0 - 2.0 * phase;
+                let y : f64 = resonators.iter_mut()
+                    .zip(FORMANT_GAINS.iter())
+                    .map(|(r, g)| r.step(source) * g)
+                    .sum();
+                let s = (y * makeup).clamp(-1.0, 1.0);
+                out.push((s * envelope(i, count) * AMPLITUDE) as i16);
+            }
+        }
+    }
+}
+
+// Renders notes at their nominal durations, with no engine padding.
+pub fn render_notes(notes: &[MoonbaseNote], voice: SynthVoice) -> Vec<i16>
+{
+    let mut samples = vec![];
+    for note in notes
+    {
+        let count = note.dur_ms.max(0) as usize * SAMPLE_RATE as usize / 1000;
+        render_note(note, count, voice, &mut samples);
+    }
+    samples
+}
+
+pub fn write_wav(samples: &[i16], outpath: &Path) -> MoonbaseResult<()>
+{
+    let spec = hound::WavSpec
+    {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+
+    let mut writer = hound::WavWriter::create(outpath, spec)?;
+    for s in samples
+    {
+        writer.write_sample(*s)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+// Offline preview backend. Notes are lengthened and the output padded the
+// same way the real engine does, so timing matches a real render.
+pub struct SynthBackend
+{
+    pub voice: SynthVoice
+}
+
+impl TtsBackend for SynthBackend
+{
+    fn name(&self) -> &str
+    {
+        match self.voice
+        {
+            SynthVoice::Tone => "synth-tone",
+            SynthVoice::Formant => "synth-formant",
+        }
+    }
+
+    fn render(&self, moonbase: &str, outpath: &Path) -> MoonbaseResult<()>
+    {
+        let notes : Vec<MoonbaseNote> = parse_moonbase_str(moonbase).into_iter().map(|mut n|
+        {
+            if n.prefix != "_"
+            {
+                n.dur_ms += NOTE_BIAS_MS;
+            }
+            n
+        })
+        .collect();
+
+        let mut samples = render_notes(&notes, self.voice);
+        samples.extend(std::iter::repeat(0).take(TAIL_SAMPLES));
+        write_wav(&samples, outpath)
+    }
+}
+
+#[cfg(test)]
+fn zero_crossings(samples: &[i16]) -> usize
+{
+    samples.windows(2).filter(|w| (w[0] < 0) != (w[1] < 0)).count()
+}
+
+#[test]
+fn synth_pitch_and_timing()
+{
+    use crate::types::ToneId;
+
+    let notes = vec![
+        MoonbaseNote { prefix: "ah".to_string(), suffix: "".to_string(), dur_ms: 1000, tone_id: ToneId(22) },
+        MoonbaseNote { prefix: "_".to_string(), suffix: "".to_string(), dur_ms: 500, tone_id: ToneId(22) },
+    ];
+
+    let samples = render_notes(&notes, SynthVoice::Tone);
+    let rate = SAMPLE_RATE as usize;
+    assert_eq!(samples.len(), rate + rate / 2);
+
+    // one second of A3 crosses zero twice per cycle
+    let crossings = zero_crossings(&samples[0..rate]);
+    assert!((crossings as i32 - 440).abs() <= 2);
+    assert!(samples[rate..].iter().all(|s| *s == 0));
+
+    let formant = render_notes(&notes, SynthVoice::Formant);
+    assert_eq!(formant.len(), samples.len());
+    assert!(formant[0..rate].iter().any(|s| s.abs() > 1000));
+}
+
+#[test]
+fn synth_backend_padding()
+{
+    let outpath = Path::new("/tmp/regolith-synth-backend.wav");
+    let backend = SynthBackend { voice: SynthVoice::Formant };
+    backend.render("[ah<933,13>][_<1000,13>]", outpath).unwrap();
+
+    let reader = hound::WavReader::open(outpath).unwrap();
+    assert_eq!(reader.len() as usize, 2 * SAMPLE_RATE as usize + TAIL_SAMPLES);
+}
@@ -0,0 +1,223 @@
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+
+use crate::moonbase::{parse_moonbase_str, MoonbaseError, MoonbaseResult, SAMPLE_RATE, NOTE_BIAS_MS, TAIL_SAMPLES};
+use crate::ratelimit::{Backoff, TokenBucket};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+pub trait TtsBackend: Send + Sync
+{
+    // short identifier; also names the backend's cache directory
+    fn name(&self) -> &str;
+
+    // renders a moonbase string to a WAV file at outpath
+    fn render(&self, moonbase: &str, outpath: &Path) -> MoonbaseResult<()>;
+}
+
+pub const DEFAULT_TTS_URL: &str = "http://tts.cyzon.us/tts";
+
+// requests per second the public service tolerates, and how many may go
+// out back to back
+pub const DEFAULT_TTS_RATE: f64 = 1.0;
+const TTS_BURST: u32 = 4;
+
+pub struct HttpBackend
+{
+    pub base_url: String,
+    // shared by every worker rendering through this backend
+    limiter: TokenBucket
+}
+
+impl HttpBackend
+{
+    pub fn new(base_url: &str) -> Self
+    {
+        HttpBackend::with_rate(base_url, DEFAULT_TTS_RATE)
+    }
+
+    pub fn with_rate(base_url: &str, requests_per_second: f64) -> Self
+    {
+        HttpBackend
+        {
+            base_url: base_url.to_string(),
+            limiter: TokenBucket::new(TTS_BURST, requests_per_second)
+        }
+    }
+}
+
+fn retry_after(resp: &reqwest::blocking::Response) -> Option<Duration>
+{
+    let secs = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+impl Default for HttpBackend
+{
+    fn default() -> Self
+    {
+        HttpBackend::new(DEFAULT_TTS_URL)
+    }
+}
+
+impl TtsBackend for HttpBackend
+{
+    fn name(&self) -> &str
+    {
+        "http"
+    }
+
+    fn render(&self, moonbase: &str, outpath: &Path) -> MoonbaseResult<()>
+    {
+        let num_attempts = 10;
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(30));
+
+        let url = format!("{}?text={}", self.base_url, moonbase);
+
+        for _ in 0..num_attempts
+        {
+            self.limiter.acquire();
+            let resp = reqwest::blocking::get(&url)?;
+            if resp.status() == StatusCode::TOO_MANY_REQUESTS ||
+               resp.status() == StatusCode::SERVICE_UNAVAILABLE
+            {
+                // honour the server's hint, but never retry sooner than
+                // the backoff allows or wait longer than its cap
+                let delay = backoff.next_delay();
+                std::thread::sleep(retry_after(&resp).map_or(delay, |d| d.clamp(delay, backoff.max)));
+                continue;
+            }
+
+            if resp.status() == StatusCode::PAYLOAD_TOO_LARGE ||
+               resp.status() == StatusCode::URI_TOO_LONG
+            {
+                return Err(MoonbaseError::TooLarge);
+            }
+
+            resp.error_for_status_ref()?;
+
+            let mut file = File::create(outpath)?;
+            let bytes = resp.bytes()?;
+            file.write_all(&bytes)?;
+            return Ok(());
+        }
+
+        Err(MoonbaseError::Generic)
+    }
+}
+
+pub struct DectalkBackend
+{
+    pub executable: PathBuf
+}
+
+impl DectalkBackend
+{
+    pub fn new(executable: &Path) -> Self
+    {
+        DectalkBackend { executable: executable.to_path_buf() }
+    }
+}
+
+impl TtsBackend for DectalkBackend
+{
+    fn name(&self) -> &str
+    {
+        "dectalk"
+    }
+
+    fn render(&self, moonbase: &str, outpath: &Path) -> MoonbaseResult<()>
+    {
+        let output = Command::new(&self.executable)
+            .arg("-pre").arg("[:phoneme on]")
+            .arg("-w").arg(outpath)
+            .arg(moonbase)
+            .output()
+            .map_err(|e| MoonbaseError::EngineError(
+                format!("Failed to run {}: {}", self.executable.display(), e)))?;
+
+        if !output.status.success()
+        {
+            return Err(MoonbaseError::EngineError(format!("{} exited with {}: {}",
+                self.executable.display(), output.status,
+                String::from_utf8_lossy(&output.stderr).trim())));
+        }
+
+        if !outpath.exists()
+        {
+            return Err(MoonbaseError::EngineError(format!(
+                "{} did not produce {}", self.executable.display(), outpath.display())));
+        }
+
+        Ok(())
+    }
+}
+
+// Offline stand-in for the real engine. Every note becomes a fixed beep
+// (silence for rests) padded the same way the engine pads its output, so
+// the rest of the pipeline sees audio of a realistic length.
+pub struct StubBackend;
+
+impl TtsBackend for StubBackend
+{
+    fn name(&self) -> &str
+    {
+        "stub"
+    }
+
+    fn render(&self, moonbase: &str, outpath: &Path) -> MoonbaseResult<()>
+    {
+        let spec = hound::WavSpec
+        {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+
+        let mut writer = hound::WavWriter::create(outpath, spec)?;
+
+        for note in parse_moonbase_str(moonbase)
+        {
+            let rest = note.prefix == "_";
+            let ms = if rest { note.dur_ms } else { note.dur_ms + NOTE_BIAS_MS };
+            let count = ms.max(0) as usize * SAMPLE_RATE as usize / 1000;
+            for i in 0..count
+            {
+                let sample = if rest
+                {
+                    0
+                }
+                else
+                {
+                    let t = i as f32 / SAMPLE_RATE as f32;
+                    ((t * 440.0 * std::f32::consts::TAU).sin() * 4000.0) as i16
+                };
+                writer.write_sample(sample)?;
+            }
+        }
+
+        for _ in 0..TAIL_SAMPLES
+        {
+            writer.write_sample(0i16)?;
+        }
+
+        writer.finalize()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn stub_rendering()
+{
+    let outpath = Path::new("/tmp/regolith-stub-rendering.wav");
+    StubBackend.render("[duw<933,19>][_<1000,13>]", outpath).unwrap();
+
+    let reader = hound::WavReader::open(outpath).unwrap();
+    assert_eq!(reader.spec().sample_rate, SAMPLE_RATE);
+    assert_eq!(reader.spec().channels, 1);
+    assert_eq!(reader.len() as usize, 2 * SAMPLE_RATE as usize + TAIL_SAMPLES);
+}
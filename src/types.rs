@@ -1,5 +1,6 @@
-use fraction::Fraction;
+use fraction::{Fraction, ToPrimitive};
 use reqwest::{Error as ReqError, StatusCode};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum CompileError
@@ -18,9 +19,156 @@ pub enum CompileError
     },
     FileError(std::io::Error),
     NetworkError(reqwest::Error),
+    EngineError(String),
     TrackTooLarge,
-    DifferingMeasureCounts(u32, usize, u32, usize),
-    EmptyTrack(u32),
+    // tracks are named by their part, if they have one, or their number
+    DifferingMeasureCounts(String, usize, String, usize),
+    // track durations, in quarter notes
+    DifferingTrackDurations(String, Fraction, String, Fraction),
+    EmptyTrack(String),
+    NestedRepeat
+    {
+        outer: Literal,
+        inner: Literal,
+    },
+    UnclosedRepeat(Literal),
+    UnopenedRepeat(Literal),
+    InvalidRepeatCount(Literal),
+    UnterminatedTie(Literal),
+    MismatchedTie
+    {
+        tie: Literal,
+        next: Literal,
+    },
+    EmptyChord(Literal),
+    UnclosedChord(Literal),
+    PitchOutOfRange(Literal),
+    UnterminatedTempoRamp(Literal),
+    ZeroTempo(Literal),
+    MismatchedTempoRamp
+    {
+        ramp: Literal,
+        target: Literal,
+    },
+    ConflictingTempoMarks
+    {
+        first: Literal,
+        second: Literal,
+    },
+    ConflictingTimeSignatures
+    {
+        first: Literal,
+        second: Literal,
+    },
+    InvalidTrackHeader(Literal),
+    UnknownPart(Literal),
+    UnknownVoice(Literal),
+    ConflictingVoices
+    {
+        first: Literal,
+        second: Literal,
+    },
+    ConflictingParts
+    {
+        first: Literal,
+        second: Literal,
+    },
+    ConflictingTrackAttributes
+    {
+        first: Literal,
+        second: Literal,
+    },
+    UnterminatedHairpin(Literal),
+    MismatchedHairpin
+    {
+        hairpin: Literal,
+        target: Literal,
+    },
+    Diagnostics(Vec<CompileError>),
+}
+
+impl CompileError
+{
+    // the source location this error is reported against, if any
+    pub fn location(&self) -> Option<&Literal>
+    {
+        match self
+        {
+            CompileError::Unexpected(_, _, literal) |
+            CompileError::InvalidSyntax(literal) |
+            CompileError::UnclosedRepeat(literal) |
+            CompileError::UnopenedRepeat(literal) |
+            CompileError::InvalidRepeatCount(literal) |
+            CompileError::UnterminatedTie(literal) |
+            CompileError::EmptyChord(literal) |
+            CompileError::UnclosedChord(literal) |
+            CompileError::PitchOutOfRange(literal) |
+            CompileError::InvalidTrackHeader(literal) |
+            CompileError::UnknownPart(literal) |
+            CompileError::UnknownVoice(literal) |
+            CompileError::UnterminatedTempoRamp(literal) |
+            CompileError::ZeroTempo(literal) |
+            CompileError::UnterminatedHairpin(literal) => Some(literal),
+            CompileError::PreambleOrder(_, _, literal) => Some(literal),
+            CompileError::EmptyMeasure(start, _) => Some(start),
+            CompileError::TimeSignatureViolation { measure, .. } => Some(&measure.start),
+            CompileError::NestedRepeat { inner, .. } => Some(inner),
+            CompileError::MismatchedTie { tie, .. } => Some(tie),
+            CompileError::MismatchedTempoRamp { ramp, .. } => Some(ramp),
+            CompileError::ConflictingTempoMarks { second, .. } => Some(second),
+            CompileError::ConflictingTimeSignatures { second, .. } => Some(second),
+            CompileError::ConflictingTrackAttributes { second, .. } => Some(second),
+            CompileError::ConflictingParts { second, .. } => Some(second),
+            CompileError::ConflictingVoices { second, .. } => Some(second),
+            CompileError::MismatchedHairpin { hairpin, .. } => Some(hairpin),
+            CompileError::Diagnostics(errors) => errors.first()?.location(),
+            _ => None,
+        }
+    }
+
+    // folds collected errors into one, ordered by where they occur;
+    // a lone error is returned unwrapped
+    pub fn from_diagnostics(mut errors: Vec<CompileError>) -> CompileError
+    {
+        errors = errors.into_iter().flat_map(|e| match e
+        {
+            CompileError::Diagnostics(inner) => inner,
+            e => vec![e],
+        })
+        .collect();
+
+        errors.sort_by_key(|e| e.location()
+            .map(|l| (0, l.filename.clone(), l.lineno, l.colno))
+            .unwrap_or((1, String::new(), 0, 0)));
+
+        if errors.len() == 1
+        {
+            return errors.pop().unwrap();
+        }
+
+        CompileError::Diagnostics(errors)
+    }
+
+    pub fn count(&self) -> usize
+    {
+        match self
+        {
+            CompileError::Diagnostics(errors) => errors.len(),
+            _ => 1,
+        }
+    }
+}
+
+pub fn collect_diagnostics<T>(value: T, errors: Vec<CompileError>) -> CompileResult<T>
+{
+    if errors.is_empty()
+    {
+        Ok(value)
+    }
+    else
+    {
+        Err(CompileError::from_diagnostics(errors))
+    }
 }
 
 impl From<std::io::Error> for CompileError
@@ -46,7 +194,9 @@ pub struct Literal
     pub filename: String,
     pub lineno: usize,
     pub colno: usize,
-    pub idno: usize
+    pub idno: usize,
+    // full text of the line this literal appears on, for diagnostics
+    pub source: Arc<str>
 }
 
 impl Literal
@@ -63,10 +213,12 @@ pub struct RegoNote
 {
     pub prefix: String,
     pub suffix: String,
-    pub beats: Fraction
+    pub beats: Fraction,
+    // held into the next note of the same track
+    pub tie: bool
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord)]
 pub enum DynamicLevel
 {
     Pianissimo,
@@ -77,9 +229,47 @@ pub enum DynamicLevel
     Fortissimo
 }
 
+// the engine's built-in speakers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Voice
+{
+    Paul,
+    Harry,
+    Frank,
+    Dennis,
+    Betty,
+    Ursula,
+    Wendy,
+    Rita,
+    Kit
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct ToneId(pub u8);
 
+// the range of tones the engine can sing, C2 through C5
+pub const MIN_TONE: u8 = 1;
+pub const MAX_TONE: u8 = 37;
+
+impl ToneId
+{
+    // the engine's tone 1 is C2 (65.41 Hz), and each step is a semitone
+    pub fn frequency(&self) -> f64
+    {
+        let ToneId(t) = *self;
+        65.406 * 2f64.powf((t as f64 - 1.0) / 12.0)
+    }
+}
+
+#[test]
+fn tone_frequencies()
+{
+    assert!((ToneId(1).frequency() - 65.41).abs() < 0.01);
+    assert!((ToneId(13).frequency() - 130.81).abs() < 0.01);
+    assert!((ToneId(22).frequency() - 220.0).abs() < 0.01);
+    assert!((ToneId(37).frequency() - 523.25).abs() < 0.01);
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Scale
 {
@@ -101,13 +291,36 @@ impl Scale
     }
 }
 
-pub fn sample_scale(scale: &Scale, degree: u8) -> ToneId
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Accidental
 {
-    let octaves: u8 = (degree - 1) / scale.steps.len() as u8;
-    let d: u8 = (degree - 1) % scale.steps.len() as u8;
+    Natural,
+    Sharp,
+    Flat
+}
+
+// None if the degree lands outside what the engine can sing
+pub fn sample_scale(scale: &Scale, degree: u8, accidental: Accidental) -> Option<ToneId>
+{
+    if degree == 0
+    {
+        return None;
+    }
+    let octaves = (degree as i32 - 1) / scale.steps.len() as i32;
+    let d = (degree as usize - 1) % scale.steps.len();
     let ToneId(root) = scale.tone_id;
-    let steps = scale.steps[0..(d as usize)].iter().sum::<u8>();
-    ToneId((octaves * 12 as u8 + root + steps))
+    let steps = scale.steps[0..d].iter().map(|s| *s as i32).sum::<i32>();
+    let tone = octaves * 12 + root as i32 + steps + match accidental
+    {
+        Accidental::Natural => 0,
+        Accidental::Sharp   => 1,
+        Accidental::Flat    => -1,
+    };
+    if tone < MIN_TONE as i32 || tone > MAX_TONE as i32
+    {
+        return None;
+    }
+    Some(ToneId(tone as u8))
 }
 
 #[test]
@@ -115,31 +328,371 @@ fn test_sample_scale()
 {
     let scale = Scale::cmajor();
 
-    assert_eq!(sample_scale(&scale, 1), ToneId(13));
-    assert_eq!(sample_scale(&scale, 2), ToneId(15));
-    assert_eq!(sample_scale(&scale, 3), ToneId(17));
-    assert_eq!(sample_scale(&scale, 4), ToneId(18));
-    assert_eq!(sample_scale(&scale, 5), ToneId(20));
-    assert_eq!(sample_scale(&scale, 6), ToneId(22));
-    assert_eq!(sample_scale(&scale, 7), ToneId(24));
-    assert_eq!(sample_scale(&scale, 8), ToneId(25));
+    assert_eq!(sample_scale(&scale, 1, Accidental::Natural), Some(ToneId(13)));
+    assert_eq!(sample_scale(&scale, 2, Accidental::Natural), Some(ToneId(15)));
+    assert_eq!(sample_scale(&scale, 3, Accidental::Natural), Some(ToneId(17)));
+    assert_eq!(sample_scale(&scale, 4, Accidental::Natural), Some(ToneId(18)));
+    assert_eq!(sample_scale(&scale, 5, Accidental::Natural), Some(ToneId(20)));
+    assert_eq!(sample_scale(&scale, 6, Accidental::Natural), Some(ToneId(22)));
+    assert_eq!(sample_scale(&scale, 7, Accidental::Natural), Some(ToneId(24)));
+    assert_eq!(sample_scale(&scale, 8, Accidental::Natural), Some(ToneId(25)));
+
+    assert_eq!(sample_scale(&scale, 4, Accidental::Sharp), Some(ToneId(19)));
+    assert_eq!(sample_scale(&scale, 7, Accidental::Flat),  Some(ToneId(23)));
+    assert_eq!(sample_scale(&scale, 3, Accidental::Flat),  Some(ToneId(16)));
+    assert_eq!(sample_scale(&scale, 8, Accidental::Sharp), Some(ToneId(26)));
+    assert_eq!(sample_scale(&scale, 0, Accidental::Natural), None);
 }
 
 pub type TimeSignature = (u8, u8);
 
+// Note lengths count units of the time signature's denominator, so a "."
+// in 6/8 is an eighth. Compound meters (6/8, 9/8, 12/16, ...) are felt in
+// dotted pulses of three units, and that pulse is what their tempo counts.
+pub fn pulse_units(ts: &TimeSignature) -> u8
+{
+    let (numer, denom) = *ts;
+    if denom >= 8 && numer > 3 && numer % 3 == 0 { 3 } else { 1 }
+}
+
+// the length of one unit of the time signature, in quarter notes
+pub fn unit_quarters(ts: &TimeSignature) -> Fraction
+{
+    Fraction::new(4u64, ts.1 as u64)
+}
+
+#[test]
+fn meter_units()
+{
+    assert_eq!(pulse_units(&(4, 4)), 1);
+    assert_eq!(pulse_units(&(6, 4)), 1);
+    assert_eq!(pulse_units(&(3, 8)), 1);
+    assert_eq!(pulse_units(&(6, 8)), 3);
+    assert_eq!(pulse_units(&(12, 16)), 3);
+    assert_eq!(pulse_units(&(7, 8)), 1);
+
+    assert_eq!(unit_quarters(&(6, 8)), Fraction::new(1u64, 2u64));
+    assert_eq!(unit_quarters(&(3, 2)), Fraction::from(2));
+}
+
+// how the staff refers to a track: by its number, or by the name of a
+// part declared for it, e.g. "PART soprano = [1]"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackRef
+{
+    Id(u32),
+    Part(String)
+}
+
+// mixer settings written in a track's header, e.g.
+// [1 "alto" gain=-3dB pan=0.3]; anything left out is unset
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackAttributes
+{
+    pub gain_db: Option<f32>,
+    // -1 is hard left, 1 hard right
+    pub pan: Option<f32>
+}
+
+// the lexer only lets finite values through
+impl Eq for TrackAttributes {}
+
+impl TrackAttributes
+{
+    // whether both give some attribute, but differently
+    pub fn conflicts_with(&self, other: &TrackAttributes) -> bool
+    {
+        fn differ<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool
+        {
+            matches!((a, b), (Some(a), Some(b)) if a != b)
+        }
+        differ(&self.gain_db, &other.gain_db) || differ(&self.pan, &other.pan)
+    }
+
+    // other's attributes, falling back to ours where it leaves them unset
+    pub fn merge(&mut self, other: &TrackAttributes)
+    {
+        self.gain_db = other.gain_db.or(self.gain_db);
+        self.pan = other.pan.or(self.pan);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Hairpin
+{
+    Crescendo,
+    Decrescendo
+}
+
+// a dynamic mark written inside the staff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DynamicMark
+{
+    Set(DynamicLevel),
+    Hairpin(Hairpin)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynamicSegment
+{
+    // in beats from the start of the section
+    pub start: Fraction,
+    pub from: DynamicLevel,
+    // the level reached at the start of the next segment
+    pub to: DynamicLevel
+}
+
+// A track's dynamics over a section: held between marks, and moving
+// smoothly from one level to the next across a hairpin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynamicMap
+{
+    segments: Vec<DynamicSegment>
+}
+
+impl DynamicMap
+{
+    pub fn constant(level: DynamicLevel) -> Self
+    {
+        DynamicMap { segments: vec![DynamicSegment { start: Fraction::from(0), from: level.clone(), to: level }] }
+    }
+
+    pub fn segments(&self) -> &[DynamicSegment]
+    {
+        &self.segments
+    }
+
+    // starts a new segment, replacing any that starts at the same beat
+    pub fn push(&mut self, start: Fraction, from: DynamicLevel, to: DynamicLevel)
+    {
+        if self.segments.last().map(|s| s.start == start).unwrap_or(false)
+        {
+            self.segments.pop();
+        }
+        self.segments.push(DynamicSegment { start, from, to });
+    }
+
+    // some per-level quantity (a gain, a velocity) at the given beat,
+    // interpolated linearly across hairpins
+    pub fn interpolate(&self, beat: &Fraction, value: &dyn Fn(&DynamicLevel) -> f64) -> f64
+    {
+        let i = self.segments.iter().rposition(|s| s.start <= *beat).unwrap_or(0);
+        let seg = &self.segments[i];
+        let (from, to) = (value(&seg.from), value(&seg.to));
+        match self.segments.get(i + 1)
+        {
+            Some(next) if next.start > seg.start =>
+            {
+                let t = ((beat - seg.start) / (next.start - seg.start)).to_f64().unwrap_or(0.0);
+                from + (to - from) * t.clamp(0.0, 1.0)
+            },
+            _ => from,
+        }
+    }
+}
+
+#[test]
+fn dynamic_map_interpolation()
+{
+    let velocity = |l: &DynamicLevel| match l
+    {
+        DynamicLevel::Piano => 40.0,
+        DynamicLevel::Forte => 100.0,
+        _ => 70.0,
+    };
+
+    let mut map = DynamicMap::constant(DynamicLevel::Piano);
+    map.push(Fraction::from(2), DynamicLevel::Piano, DynamicLevel::Forte);
+    map.push(Fraction::from(6), DynamicLevel::Forte, DynamicLevel::Forte);
+
+    assert_eq!(map.interpolate(&Fraction::from(1), &velocity), 40.0);
+    assert_eq!(map.interpolate(&Fraction::from(2), &velocity), 40.0);
+    assert_eq!(map.interpolate(&Fraction::from(3), &velocity), 55.0);
+    assert_eq!(map.interpolate(&Fraction::from(6), &velocity), 100.0);
+    assert_eq!(map.interpolate(&Fraction::from(9), &velocity), 100.0);
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TempoRamp
+{
+    Ritardando,
+    Accelerando
+}
+
+// a tempo mark written inside the staff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TempoMark
+{
+    Set(u16),
+    Ramp(TempoRamp)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoSegment
+{
+    // in beats from the start of the section
+    pub start: Fraction,
+    pub from_bpm: u16,
+    // the tempo reached at the start of the next segment
+    pub to_bpm: u16
+}
+
+// A section's tempo over its beats. Between marks the tempo holds steady;
+// across a ramp the milliseconds per beat change linearly, reaching the
+// target tempo where the next segment begins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap
+{
+    segments: Vec<TempoSegment>,
+    // units of the time signature in each beat that the tempo counts
+    pulse: u8
+}
+
+impl TempoMap
+{
+    pub fn new(bpm: u16, pulse: u8) -> Self
+    {
+        TempoMap
+        {
+            segments: vec![TempoSegment { start: Fraction::from(0), from_bpm: bpm, to_bpm: bpm }],
+            pulse: pulse.max(1)
+        }
+    }
+
+    pub fn constant(bpm: u16) -> Self
+    {
+        TempoMap::new(bpm, 1)
+    }
+
+    fn beat_period_ms(&self, bpm: u16) -> f64
+    {
+        60000.0 / bpm as f64 / self.pulse as f64
+    }
+
+    pub fn segments(&self) -> &[TempoSegment]
+    {
+        &self.segments
+    }
+
+    // starts a new segment, replacing any that starts at the same beat
+    pub fn push(&mut self, start: Fraction, from_bpm: u16, to_bpm: u16)
+    {
+        if self.segments.last().map(|s| s.start == start).unwrap_or(false)
+        {
+            self.segments.pop();
+        }
+        self.segments.push(TempoSegment { start, from_bpm, to_bpm });
+    }
+
+    pub fn final_bpm(&self) -> u16
+    {
+        self.segments.last().map(|s| s.to_bpm).unwrap_or(120)
+    }
+
+    fn segment_end(&self, i: usize) -> Option<f64>
+    {
+        self.segments.get(i + 1).map(|s| s.start.to_f64().unwrap_or(0.0))
+    }
+
+    // milliseconds per unit at the given beat
+    pub fn period_at(&self, beat: &Fraction) -> f64
+    {
+        let beat = beat.to_f64().unwrap_or(0.0);
+        for (i, seg) in self.segments.iter().enumerate().rev()
+        {
+            let start = seg.start.to_f64().unwrap_or(0.0);
+            if start > beat && i > 0
+            {
+                continue;
+            }
+            let (from, to) = (self.beat_period_ms(seg.from_bpm), self.beat_period_ms(seg.to_bpm));
+            return match self.segment_end(i)
+            {
+                Some(end) if end > start => from + (to - from) * (beat - start).max(0.0) / (end - start),
+                _ => from,
+            };
+        }
+        self.beat_period_ms(120)
+    }
+
+    // milliseconds from the start of the section to the given beat
+    pub fn millis_at(&self, beat: &Fraction) -> f64
+    {
+        let beat = beat.to_f64().unwrap_or(0.0);
+        let mut ms = 0.0;
+        for (i, seg) in self.segments.iter().enumerate()
+        {
+            let start = seg.start.to_f64().unwrap_or(0.0);
+            if start >= beat
+            {
+                break;
+            }
+            let (from, to) = (self.beat_period_ms(seg.from_bpm), self.beat_period_ms(seg.to_bpm));
+            match self.segment_end(i)
+            {
+                Some(end) if end > start =>
+                {
+                    let x = beat.min(end) - start;
+                    ms += from * x + (to - from) * x * x / (2.0 * (end - start));
+                },
+                Some(_) => (),
+                None => ms += from * (beat - start),
+            }
+        }
+        ms
+    }
+
+    pub fn duration_ms(&self, start: &Fraction, beats: &Fraction) -> i32
+    {
+        (self.millis_at(&(start + beats)) - self.millis_at(start)).round() as i32
+    }
+}
+
+#[test]
+fn tempo_map_timing()
+{
+    let steady = TempoMap::constant(120);
+    assert_eq!(steady.duration_ms(&Fraction::from(3), &Fraction::new(3u64, 2u64)), 750);
+
+    // 60 BPM for 4 beats, slowing to 30 BPM over the next 4, then held
+    let mut map = TempoMap::constant(60);
+    map.push(Fraction::from(4), 60, 30);
+    map.push(Fraction::from(8), 30, 30);
+
+    assert_eq!(map.duration_ms(&Fraction::from(0), &Fraction::from(4)), 4000);
+    assert_eq!(map.duration_ms(&Fraction::from(4), &Fraction::from(4)), 6000);
+    assert_eq!(map.duration_ms(&Fraction::from(8), &Fraction::from(1)), 2000);
+    assert_eq!(map.duration_ms(&Fraction::from(4), &Fraction::from(1)), 1125);
+    assert_eq!(map.period_at(&Fraction::from(6)), 1500.0);
+    assert_eq!(map.period_at(&Fraction::from(9)), 2000.0);
+    assert_eq!(map.final_bpm(), 30);
+
+    // in 6/8, 60 BPM counts dotted quarters, so each eighth is a third
+    let compound = TempoMap::new(60, 3);
+    assert_eq!(compound.duration_ms(&Fraction::from(0), &Fraction::from(6)), 2000);
+    assert_eq!(compound.duration_ms(&Fraction::from(1), &Fraction::from(1)), 333);
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Token
 {
-    Track(u32),
+    Track(TrackRef, TrackAttributes),
+    Part(String, u32),
+    Voice(Voice),
     Tempo(u16),
     AbsolutePitch(ToneId),
     Note(RegoNote),
     Scale(Scale),
-    ScaleDegree(u8),
+    ScaleDegree(u8, Accidental),
     Dynamic(DynamicLevel),
     MeasureBar(bool, bool),
+    EndRepeat(u8),
     Section(String),
     TimeSignature(TimeSignature),
+    TempoRamp(TempoRamp),
+    Hairpin(Hairpin),
+    ChordOpen,
+    ChordClose,
     Endline(),
 }
 
@@ -150,7 +703,9 @@ pub struct NoteDecl
 {
     pub note: RegoNote,
     pub note_literal: Literal,
-    pub tone_id: ToneId
+    pub tone_id: ToneId,
+    // further pitches sung alongside tone_id, if this note is a chord
+    pub chord: Vec<ToneId>
 }
 
 #[derive(Debug, Clone)]
@@ -160,8 +715,12 @@ pub struct Measure
     pub end: Literal,
     pub close: bool,
     pub open: bool,
+    pub repeats: u8,
     pub track: u32,
-    pub notes: Vec<NoteDecl>
+    pub notes: Vec<NoteDecl>,
+    // inline tempo marks, by their offset in beats into the measure
+    pub tempo_marks: Vec<(Fraction, Literal, TempoMark)>,
+    pub dynamic_marks: Vec<(Fraction, Literal, DynamicMark)>
 }
 
 impl Measure